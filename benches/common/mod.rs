@@ -0,0 +1,50 @@
+// Synthetic corpus shared by the benchmarks
+
+use ebm25::CorpusDocument;
+use rand::{rngs::StdRng, Rng};
+
+const DOCUMENTS: usize = 100_000;
+const VOCABULARY: usize = 20_000;
+const MIN_WORDS: usize = 50;
+const MAX_WORDS: usize = 250;
+
+fn vocabulary(rng: &mut StdRng) -> Vec<String> {
+    (0..VOCABULARY)
+        .map(|_| {
+            let len = rng.gen_range(3..10);
+            (0..len)
+                .map(|_| rng.gen_range(b'a'..=b'z') as char)
+                .collect()
+        })
+        .collect()
+}
+
+// Word frequencies are skewed towards the start of the vocabulary,
+// so common terms have long postings as in natural text
+pub fn corpus(rng: &mut StdRng, documents: usize) -> Vec<CorpusDocument> {
+    let words = vocabulary(rng);
+    (0..documents)
+        .map(|_| {
+            let len = rng.gen_range(MIN_WORDS..MAX_WORDS);
+            let body: Vec<&str> = (0..len)
+                .map(|_| {
+                    let skewed = rng.gen::<f64>().powi(3);
+                    words[(skewed * VOCABULARY as f64) as usize].as_str()
+                })
+                .collect();
+            CorpusDocument {
+                id: None,
+                title: String::new(),
+                body: body.join(" "),
+            }
+        })
+        .collect()
+}
+
+// EBM25_BENCH_DOCUMENTS sets another corpus size
+pub fn corpus_size() -> usize {
+    std::env::var("EBM25_BENCH_DOCUMENTS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DOCUMENTS)
+}
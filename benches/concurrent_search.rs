@@ -0,0 +1,130 @@
+// Search throughput of the server index while a bulk update is running.
+// `mutex` is the previous server state, a single `Mutex<HashMap>` that
+// an update holds for a whole batch, `sharded` is `EncryptedIndex`.
+// Readers and the writer run on their own threads, so the numbers are
+// only meaningful on a machine with more cores than READERS + 1.
+//
+//   cargo bench --bench concurrent_search
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ebm25::{EncryptedIndex, EncryptedTerm2Document};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+const INDEX_SIZE: usize = 200_000;
+// same as the batch of a streamed upload on the server
+const UPDATE_BATCH_SIZE: usize = 10_000;
+const UPDATE_BATCHES: usize = 10;
+const READERS: usize = 4;
+const LOOKUPS_PER_READER: usize = 2_000;
+
+type Record = (Vec<u8>, Vec<u8>);
+
+trait SearchIndex: Sync {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    fn insert_batch(&self, records: &[Record]);
+}
+
+impl SearchIndex for Mutex<HashMap<Vec<u8>, Vec<u8>>> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lock().unwrap().get(key).cloned()
+    }
+
+    fn insert_batch(&self, records: &[Record]) {
+        let records = records.to_vec();
+        self.lock().unwrap().extend(records);
+    }
+}
+
+impl SearchIndex for EncryptedIndex {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        EncryptedIndex::get(self, key)
+    }
+
+    fn insert_batch(&self, records: &[Record]) {
+        self.extend(
+            records
+                .iter()
+                .map(|(k, v)| EncryptedTerm2Document::new(k.clone(), v.clone()))
+                .collect(),
+        );
+    }
+}
+
+// Index keys and values have the sizes of HMAC outputs and encrypted metas
+fn records(rng: &mut StdRng, count: usize) -> Vec<Record> {
+    (0..count)
+        .map(|_| {
+            let key: [u8; 32] = rng.gen();
+            let value: [u8; 24] = rng.gen();
+            (key.to_vec(), value.to_vec())
+        })
+        .collect()
+}
+
+// Every reader looks up existing keys, while `updating` another thread
+// keeps inserting batches of new records
+fn search<I: SearchIndex>(index: &I, keys: &[Vec<u8>], updates: &[Vec<Record>], updating: bool) {
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        if updating {
+            scope.spawn(|| {
+                for batch in updates.iter().cycle() {
+                    if done.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    index.insert_batch(batch);
+                }
+            });
+        }
+
+        let readers: Vec<_> = (0..READERS)
+            .map(|reader| {
+                scope.spawn(move || {
+                    for i in 0..LOOKUPS_PER_READER {
+                        let key = &keys[(reader * LOOKUPS_PER_READER + i) % keys.len()];
+                        assert!(index.get(key).is_some());
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+}
+
+fn bench_concurrent_search(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let initial = records(&mut rng, INDEX_SIZE);
+    let updates: Vec<Vec<Record>> = (0..UPDATE_BATCHES)
+        .map(|_| records(&mut rng, UPDATE_BATCH_SIZE))
+        .collect();
+    let keys: Vec<Vec<u8>> = initial.iter().map(|(k, _)| k.clone()).collect();
+
+    let mutex = Mutex::new(HashMap::new());
+    mutex.insert_batch(&initial);
+    let sharded = EncryptedIndex::new();
+    sharded.insert_batch(&initial);
+
+    let mut group = c.benchmark_group("concurrent_search");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements((READERS * LOOKUPS_PER_READER) as u64));
+    for updating in [false, true] {
+        let label = if updating { "during_update" } else { "idle" };
+        group.bench_with_input(BenchmarkId::new("mutex", label), &updating, |b, &u| {
+            b.iter(|| search(&mutex, &keys, &updates, u))
+        });
+        group.bench_with_input(BenchmarkId::new("sharded", label), &updating, |b, &u| {
+            b.iter(|| search(&sharded, &keys, &updates, u))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_concurrent_search);
+criterion_main!(benches);
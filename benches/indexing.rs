@@ -0,0 +1,76 @@
+// Bulk indexing of a synthetic corpus of 100k documents: `Indexer::import`
+// one document at a time against `Indexer::import_all`, which tokenizes
+// and encrypts documents in parallel, and encryption of the index.
+// Every parallel step is measured on one thread and on all cores, so the
+// numbers are only meaningful on a machine with several cores.
+// EBM25_BENCH_DOCUMENTS sets another corpus size.
+//
+//   cargo bench --bench indexing
+
+mod common;
+
+use common::{corpus, corpus_size};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ebm25::Indexer;
+use rand::{rngs::StdRng, SeedableRng};
+use rayon::ThreadPool;
+
+fn pools() -> Vec<(String, ThreadPool)> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut threads = vec![1];
+    if cores > 1 {
+        threads.push(cores);
+    }
+    threads
+        .into_iter()
+        .map(|n| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(n)
+                .build()
+                .unwrap();
+            (format!("{}_threads", n), pool)
+        })
+        .collect()
+}
+
+fn bench_indexing(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let documents = corpus_size();
+    let corpus = corpus(&mut rng, documents);
+    let pools = pools();
+
+    let mut group = c.benchmark_group("indexing");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(documents as u64));
+
+    group.bench_function("import", |b| {
+        b.iter(|| {
+            let mut indexer = Indexer::new();
+            for document in corpus.iter().cloned() {
+                indexer.import(document).unwrap();
+            }
+            indexer
+        })
+    });
+    for (label, pool) in pools.iter() {
+        group.bench_with_input(BenchmarkId::new("import_all", label), pool, |b, pool| {
+            b.iter(|| {
+                let mut indexer = Indexer::new();
+                pool.install(|| indexer.import_all(corpus.clone()).unwrap());
+                indexer
+            })
+        });
+    }
+
+    let mut indexer = Indexer::new();
+    indexer.import_all(corpus.clone()).unwrap();
+    for (label, pool) in pools.iter() {
+        group.bench_with_input(BenchmarkId::new("encrypt_index", label), pool, |b, pool| {
+            b.iter(|| pool.install(|| indexer.get_encrypted_index()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_indexing);
+criterion_main!(benches);
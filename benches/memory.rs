@@ -0,0 +1,83 @@
+// Heap held by the indexer after indexing the synthetic corpus of the
+// indexing benchmark. Documents are handed off encrypted and only
+// postings stay in memory, the previous layout kept every document and
+// a copy of it in each of its postings, it is estimated from the corpus.
+// For 100k documents (100 MiB of text, 14M postings) the estimate is about
+// 17 GiB, the indexer holds about 370 MiB. EBM25_BENCH_DOCUMENTS sets
+// another corpus size.
+//
+//   cargo bench --bench memory
+
+mod common;
+
+use common::{corpus, corpus_size};
+use ebm25::{group_by, tokenize, Document, Indexer};
+use rand::{rngs::StdRng, SeedableRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counts bytes allocated on the heap
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn mib(bytes: usize) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+fn main() {
+    let documents = corpus_size();
+    let corpus = corpus(&mut StdRng::seed_from_u64(42), documents);
+    let plaintext: usize = corpus.iter().map(|d| d.title.len() + d.body.len()).sum();
+
+    // the map of documents and one clone per distinct term of a document
+    let previous: usize = corpus
+        .iter()
+        .map(|d| {
+            let document = std::mem::size_of::<Document>() + d.title.len() + d.body.len();
+            document * (1 + group_by(&tokenize(&d.body)).len())
+        })
+        .sum();
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let mut indexer = Indexer::new();
+    let encrypted = indexer.import_all(corpus).unwrap();
+    let ciphertext: usize = encrypted.iter().map(|d| d.ciphertext.len()).sum();
+    drop(encrypted);
+    let held = ALLOCATED.load(Ordering::Relaxed) - before;
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    let postings: usize = indexer.snapshot().postings.values().map(Vec::len).sum();
+
+    println!("documents                 {}", documents);
+    println!("postings                  {}", postings);
+    println!("plaintext                 {:>10.1} MiB", mib(plaintext));
+    println!("ciphertext handed off     {:>10.1} MiB", mib(ciphertext));
+    println!("previous layout, estimate {:>10.1} MiB", mib(previous));
+    println!("indexer heap              {:>10.1} MiB", mib(held));
+    println!(
+        "                          {:>10.1} bytes per posting",
+        held as f64 / postings as f64
+    );
+    println!("peak while indexing       {:>10.1} MiB", mib(peak));
+}
@@ -0,0 +1,27 @@
+#![no_main]
+
+// Documents come from the server, so any bytes it sends must be rejected
+// with an error: decoding, decrypting and decompressing must never panic
+use ebm25::{EncryptedDocument, Indexer, MasterKey, WireFormat};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let indexer = Indexer::with_master_key(MasterKey::from_bytes([7; 32]));
+    for format in [WireFormat::Binary, WireFormat::Json] {
+        // a response to a batch fetch
+        if let Ok(documents) = format.decode::<Vec<Option<EncryptedDocument>>>(data) {
+            for document in documents.into_iter().flatten() {
+                assert!(indexer.decrypt(document.id, &document).is_err());
+            }
+        }
+
+        let Ok(document) = format.decode::<EncryptedDocument>(data) else {
+            continue;
+        };
+        let decoded: EncryptedDocument = format.decode(&format.encode(&document)).unwrap();
+        assert_eq!(decoded, document);
+
+        // forged ciphertexts do not authenticate
+        assert!(indexer.decrypt(document.id, &document).is_err());
+    }
+});
@@ -0,0 +1,38 @@
+#![no_main]
+
+// Index uploads as one message (`/index`) and as a stream of records
+// (`/index/stream`), the first byte splits the stream into chunks
+use ebm25::{
+    EncryptedIndex, EncryptedIndexUpdate, EncryptedTerm2Document, RecordDecoder, WireFormat,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((split, body)) = data.split_first() else {
+        return;
+    };
+    'formats: for format in [WireFormat::Binary, WireFormat::Json] {
+        let index = EncryptedIndex::new();
+        if let Ok(update) = format.decode::<EncryptedIndexUpdate>(body) {
+            index.update(&update);
+            assert!(index.len() <= update.len());
+        }
+
+        let mut decoder = RecordDecoder::new(format);
+        let mut records = Vec::new();
+        for chunk in body.chunks(*split as usize + 1) {
+            decoder.push(chunk);
+            while let Some(record) = decoder.next_record::<EncryptedTerm2Document>() {
+                match record {
+                    Ok(record) => records.push(record),
+                    // the server drops the stream here, the other format still runs
+                    Err(_) => continue 'formats,
+                }
+            }
+        }
+        if let Ok(Some(record)) = decoder.finish::<EncryptedTerm2Document>() {
+            records.push(record);
+        }
+        index.extend(records);
+    }
+});
@@ -0,0 +1,52 @@
+#![no_main]
+
+// Search requests are decoded by the server, the values it answers with
+// are scored by the client, which must not trust their number or lengths
+use ebm25::{EncryptedIndex, Indexer, MasterKey, WireFormat, INDEX_VALUE_SIZE};
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+
+struct Fixture {
+    indexer: Indexer,
+    index: EncryptedIndex,
+}
+
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| {
+        let mut indexer = Indexer::with_master_key(MasterKey::from_bytes([7; 32]));
+        indexer.add("the quick brown fox".to_string());
+        indexer.add("a lazy brown dog".to_string());
+        let index = EncryptedIndex::new();
+        index.update(&indexer.get_encrypted_index());
+        Fixture { indexer, index }
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Fixture { indexer, index } = fixture();
+    let query = indexer.query("brown fox".to_string());
+
+    for format in [WireFormat::Binary, WireFormat::Json] {
+        let Ok(payload) = format.decode::<Vec<Vec<u8>>>(data) else {
+            continue;
+        };
+
+        // server side, the payload is a list of index keys
+        let values: Vec<Vec<u8>> = payload
+            .iter()
+            .map(|key| index.get(key).unwrap_or_default())
+            .collect();
+        assert_eq!(values.len(), payload.len());
+
+        // client side, the payload is a list of index values,
+        // values of a wrong length are rejected
+        for (_, score) in indexer.rank(&query, &payload) {
+            assert!(!score.is_nan());
+        }
+        for (term, value) in query.terms.iter().zip(&payload) {
+            let meta = indexer.meta(term, value);
+            assert_eq!(meta.is_ok(), value.len() == INDEX_VALUE_SIZE);
+        }
+    }
+});
@@ -0,0 +1,540 @@
+use crate::state::{ClientState, Result};
+use ebm25::{
+    CollectionStats, CorpusDocument, Document, EncryptedDocument, EncryptedIndexUpdate, Indexer,
+    IngestReport, KeyEpoch, WireFormat,
+};
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ops::Add;
+use tracing::{debug, info, warn};
+
+// Number of index records sent as one chunk of a streamed upload
+const STREAM_CHUNK_SIZE: usize = 1024;
+
+// Ciphertext bytes sent in one batch upload, JSON inflates them up
+// to 4x, which still fits into the default body limit of the server
+const DOCUMENT_BATCH_BYTES: usize = 256 * 1024;
+
+// Number of documents requested in one batch fetch
+const FETCH_BATCH_SIZE: usize = 100;
+
+// Number of documents at the start of `documents` that go into one batch
+// upload, a batch has at least one document however large it is
+fn batch_len(documents: &[EncryptedDocument]) -> usize {
+    let mut batch_bytes = 0;
+    documents
+        .iter()
+        .take_while(|document| {
+            let first = batch_bytes == 0;
+            batch_bytes += document.ciphertext.len();
+            first || batch_bytes <= DOCUMENT_BATCH_BYTES
+        })
+        .count()
+}
+
+// Numbers of documents and index records uploaded by a flush
+pub struct FlushReport {
+    pub documents: usize,
+    pub index: IngestReport,
+}
+
+// Document found by a search with its BM25 score
+pub struct SearchResult {
+    pub document: Document,
+    pub score: f64,
+}
+
+pub struct Client {
+    indexer: Indexer,
+    // sends the API token with every request
+    http: reqwest::Client,
+    // URL of the collection of the indexer, all routes are relative to it
+    url: String,
+    format: WireFormat,
+    // documents that are indexed but not stored on the server yet,
+    // the indexer does not keep them
+    pending: Vec<EncryptedDocument>,
+    // key epoch of a rotation that was started but not committed yet
+    staged: Option<KeyEpoch>,
+}
+
+impl Client {
+    pub fn new(
+        url: &str,
+        token: &str,
+        format: WireFormat,
+        indexer: Indexer,
+        pending: Vec<EncryptedDocument>,
+        staged: Option<KeyEpoch>,
+    ) -> Result<Self> {
+        let mut authorization = HeaderValue::from_str(&format!("Bearer {}", token))?;
+        authorization.set_sensitive(true);
+        let http = reqwest::Client::builder()
+            .default_headers(HeaderMap::from_iter([(AUTHORIZATION, authorization)]))
+            .build()?;
+
+        Ok(Self {
+            url: format!(
+                "{}/collections/{}",
+                url.trim_end_matches('/'),
+                indexer.collection()
+            ),
+            indexer,
+            http,
+            format,
+            pending,
+            staged,
+        })
+    }
+
+    pub fn indexer(&self) -> &Indexer {
+        &self.indexer
+    }
+
+    // Documents added locally that are not stored on the server yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn state(&self) -> ClientState {
+        ClientState {
+            indexer: self.indexer.snapshot(),
+            pending: self.pending.clone(),
+            staged: self.staged,
+        }
+    }
+
+    // Creates the collection of the indexer under its key epoch unless
+    // it already exists, returns whether it was created
+    pub async fn create_collection(&self) -> Result<bool> {
+        let response = self
+            .http
+            .put(&self.url)
+            .query(&[("epoch", self.indexer.epoch())])
+            .send()
+            .await?;
+        if response.status() == StatusCode::CONFLICT {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    pub async fn stats(&self) -> Result<CollectionStats> {
+        self.find_stats().await?.ok_or_else(|| {
+            format!(
+                "collection {} does not exist on the server, run `ebm25 flush` first",
+                self.indexer.collection()
+            )
+            .into()
+        })
+    }
+
+    // Stats of the collection, None if it does not exist
+    async fn find_stats(&self) -> Result<Option<CollectionStats>> {
+        let response = self
+            .http
+            .get(&self.url)
+            .header(ACCEPT, self.format.content_type())
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(self.read(response.error_for_status()?).await?))
+    }
+
+    // Documents and index entries are only uploaded under the key epoch
+    // the server is at, entries of another epoch would never be found
+    async fn check_epoch(&self) -> Result<()> {
+        let stats = self.stats().await?;
+        if let Some(staged) = stats.staged_epoch {
+            return Err(format!(
+                "key rotation to epoch {} is staged on the server, run `ebm25 rotate` to rotate again",
+                staged
+            )
+            .into());
+        }
+        if stats.epoch != self.indexer.epoch() {
+            return Err(format!(
+                "collection {} is at key epoch {}, the client at {}",
+                stats.name,
+                stats.epoch,
+                self.indexer.epoch()
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn post<T: Serialize + ?Sized>(&self, url: String, value: &T) -> reqwest::RequestBuilder {
+        self.encoded(self.http.post(url), value)
+    }
+
+    fn encoded<T: Serialize + ?Sized>(
+        &self,
+        request: reqwest::RequestBuilder,
+        value: &T,
+    ) -> reqwest::RequestBuilder {
+        request
+            .header(CONTENT_TYPE, self.format.content_type())
+            .header(ACCEPT, self.format.content_type())
+            .body(self.format.encode(value))
+    }
+
+    // Streams index records to `url` chunk by chunk, so large
+    // updates do not hit the request body limit of the server
+    async fn stream_index(
+        &self,
+        url: String,
+        update: EncryptedIndexUpdate,
+    ) -> Result<IngestReport> {
+        let format = self.format;
+        let chunks = futures_util::stream::iter(update.into_records())
+            .chunks(STREAM_CHUNK_SIZE)
+            .map(move |records| {
+                let chunk: Vec<u8> = records
+                    .iter()
+                    .flat_map(|r| format.encode_record(r))
+                    .collect();
+                Ok::<_, std::io::Error>(chunk)
+            });
+
+        let response = self
+            .http
+            .post(url)
+            .header(CONTENT_TYPE, format.content_type())
+            .header(ACCEPT, format.content_type())
+            .body(reqwest::Body::wrap_stream(chunks))
+            .send()
+            .await?
+            .error_for_status()?;
+        self.read(response).await
+    }
+
+    // Uploads pending documents in batches bounded by the ciphertext size,
+    // every uploaded batch is dropped from the pending documents, so a failed
+    // upload can be continued without overwriting stored documents. A batch
+    // whose response was lost is sent again as is, the server accepts
+    // ciphertexts that are already stored
+    async fn upload_documents(&mut self) -> Result<usize> {
+        let url = self.url.clone().add("/documents:batch");
+        let mut uploaded = 0;
+
+        while !self.pending.is_empty() {
+            let batch_len = batch_len(&self.pending);
+            self.post(url.clone(), &self.pending[..batch_len])
+                .send()
+                .await?
+                .error_for_status()?;
+            self.pending.drain(..batch_len);
+            uploaded += batch_len;
+        }
+        Ok(uploaded)
+    }
+
+    // Fetches documents in batches, missing documents are skipped
+    async fn fetch_documents(&self, ids: &[u64]) -> Result<Vec<(u64, EncryptedDocument)>> {
+        let url = self.url.clone().add("/documents:get");
+        let mut documents = Vec::with_capacity(ids.len());
+
+        for batch in ids.chunks(FETCH_BATCH_SIZE) {
+            let response = self
+                .post(url.clone(), &batch)
+                .send()
+                .await?
+                .error_for_status()?;
+            let found: Vec<Option<EncryptedDocument>> = self.read(response).await?;
+            documents.extend(
+                batch
+                    .iter()
+                    .zip(found)
+                    .filter_map(|(id, document)| document.map(|d| (*id, d))),
+            );
+        }
+        Ok(documents)
+    }
+
+    // Decodes response body according to its Content-Type
+    async fn read<T: DeserializeOwned>(&self, response: reqwest::Response) -> Result<T> {
+        let format = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(WireFormat::from_content_type)
+            .unwrap_or(WireFormat::Json);
+        let body = response.bytes().await?;
+        Ok(format.decode(&body)?)
+    }
+
+    // Indexes and encrypts the documents, they are uploaded by `flush`
+    pub fn import(&mut self, documents: Vec<CorpusDocument>) -> Result<Vec<u64>> {
+        let encrypted = self.indexer.import_all(documents)?;
+        let ids = encrypted.iter().map(|document| document.id).collect();
+        self.pending.extend(encrypted);
+        Ok(ids)
+    }
+
+    // Ids of indexed documents that are stored on the server
+    fn stored_ids(&self) -> Vec<u64> {
+        let pending: HashSet<u64> = self.pending.iter().map(|document| document.id).collect();
+        let mut ids: Vec<u64> = self
+            .indexer
+            .document_ids()
+            .into_iter()
+            .filter(|id| !pending.contains(id))
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    // Fetches and decrypts all documents stored on the server in the order of their ids
+    pub async fn export(&self) -> Result<Vec<Document>> {
+        let ids = self.stored_ids();
+        let mut documents = Vec::with_capacity(ids.len());
+        for (id, document) in self.fetch_documents(&ids).await? {
+            documents.push(self.indexer.decrypt(id, &document)?);
+        }
+        if documents.len() < ids.len() {
+            warn!(
+                missing = ids.len() - documents.len(),
+                "documents are missing on the server"
+            );
+        }
+        Ok(documents)
+    }
+
+    // Uploads documents that are not stored on the server yet and the index
+    // entries that changed since the last flush, a collection that had to
+    // be created gets the whole index. A failed upload is sent again as a
+    // whole, rewriting entries and tombstones the server has does no harm
+    pub async fn flush(&mut self) -> Result<FlushReport> {
+        // documents are not kept by the client, if the server lost
+        // them they can only be added again from their source
+        let created = self.create_collection().await?;
+        if created && self.indexer.len() > self.pending.len() {
+            warn!(
+                collection = self.indexer.collection(),
+                missing = self.indexer.len() - self.pending.len(),
+                "collection was created again, documents flushed before are lost"
+            );
+        }
+        self.check_epoch().await?;
+        let documents = self.upload_documents().await?;
+        let index_update = if created {
+            self.indexer.get_encrypted_index()
+        } else {
+            self.indexer.index_changes()
+        };
+
+        let report = self
+            .stream_index(self.url.clone().add("/index/stream"), index_update)
+            .await?;
+        self.indexer.mark_uploaded();
+        info!(
+            collection = self.indexer.collection(),
+            records = report.records,
+            total = report.total,
+            "index updated"
+        );
+        Ok(FlushReport {
+            documents,
+            index: report,
+        })
+    }
+
+    // Fetches a document from the server and decrypts it
+    pub async fn get(&self, id: u64) -> Result<Option<Document>> {
+        let response = self
+            .http
+            .get(self.url.clone().add("/documents/").add(&id.to_string()))
+            .header(ACCEPT, self.format.content_type())
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let document: EncryptedDocument = self.read(response.error_for_status()?).await?;
+        Ok(Some(self.indexer.decrypt(id, &document)?))
+    }
+
+    // Removes the document from the server and then locally, the index
+    // has to be flushed so its postings are dropped on the server too
+    pub async fn delete(&mut self, id: u64) -> Result<bool> {
+        if !self.indexer.contains(id) {
+            return Ok(false);
+        }
+        if !self.pending.iter().any(|document| document.id == id) {
+            let response = self
+                .http
+                .delete(self.url.clone().add("/documents/").add(&id.to_string()))
+                .send()
+                .await?;
+            if response.status() != StatusCode::NOT_FOUND {
+                response.error_for_status()?;
+            }
+        }
+        self.pending.retain(|document| document.id != id);
+        self.indexer.remove(id);
+        Ok(true)
+    }
+
+    // Starts key rotation: pending documents are flushed, they are encrypted
+    // under the old keys, then keys of the next epoch are derived. A rotation
+    // that is staged on the server without the client knowing about it was
+    // started by a run that failed before it saved the new epoch, it is
+    // dropped. The state has to be saved before `finish_rotation`, so the
+    // client never lags behind the epoch of the server
+    pub async fn start_rotation(&mut self) -> Result<KeyEpoch> {
+        if let Some(staged) = self.find_stats().await?.and_then(|s| s.staged_epoch) {
+            self.abort_rotation(staged).await?;
+        }
+        self.flush().await?;
+        let epoch = self.indexer.rotate_keys();
+        self.staged = Some(epoch);
+        Ok(epoch)
+    }
+
+    // Stages and commits the rotation started by `start_rotation`, also when
+    // a previous run failed halfway: staging is run again from the start,
+    // and a commit whose response was lost is recognized by the epoch of
+    // the server. Returns the epoch, None if no rotation was started
+    pub async fn finish_rotation(&mut self) -> Result<Option<KeyEpoch>> {
+        let Some(epoch) = self.staged else {
+            return Ok(None);
+        };
+        // a collection that is gone is created again under the new epoch
+        match self.find_stats().await? {
+            Some(stats) if stats.epoch != epoch => {
+                self.stage_rotation(epoch).await?;
+                self.commit_rotation(epoch).await?;
+            }
+            // the server has the index as it was staged, documents
+            // may have been added since then
+            Some(_) => {
+                let index_update = self.indexer.get_encrypted_index();
+                self.stream_index(self.url.clone().add("/index/stream"), index_update)
+                    .await?;
+            }
+            None => {}
+        }
+        self.indexer.mark_uploaded();
+        self.staged = None;
+        Ok(Some(epoch))
+    }
+
+    // Stored documents are fetched, re-encrypted and uploaded in batches
+    // together with the re-derived index, which is streamed like on flush
+    async fn stage_rotation(&self, epoch: KeyEpoch) -> Result<()> {
+        let ids = self.stored_ids();
+        let documents = self.fetch_documents(&ids).await?;
+        let prefix = self.rotation_url(epoch);
+
+        let rotated = documents
+            .iter()
+            .map(|(id, document)| self.indexer.reencrypt(*id, document))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let url = prefix.clone().add("/documents:batch");
+        let mut rest = &rotated[..];
+        while !rest.is_empty() {
+            let (batch, next) = rest.split_at(batch_len(rest));
+            self.post(url.clone(), batch)
+                .send()
+                .await?
+                .error_for_status()?;
+            rest = next;
+        }
+
+        let index_update = self.indexer.get_encrypted_index();
+        self.stream_index(prefix.add("/index/stream"), index_update)
+            .await?;
+        Ok(())
+    }
+
+    // Switches the server to the staged epoch, after that it drops
+    // the documents and index of the previous epoch
+    async fn commit_rotation(&self, epoch: KeyEpoch) -> Result<()> {
+        self.http
+            .post(self.rotation_url(epoch).add("/commit"))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn abort_rotation(&self, epoch: KeyEpoch) -> Result<()> {
+        let response = self.http.delete(self.rotation_url(epoch)).send().await?;
+        if response.status() != StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+        }
+        warn!(
+            collection = self.indexer.collection(),
+            epoch, "unfinished key rotation dropped"
+        );
+        Ok(())
+    }
+
+    fn rotation_url(&self, epoch: KeyEpoch) -> String {
+        self.url.clone().add("/rotate/").add(&epoch.to_string())
+    }
+
+    // Ids and scores of the top k documents, documents are not fetched
+    pub async fn rank(&self, text: &str, top_k: usize) -> Result<Vec<(u64, f64)>> {
+        let query = self.indexer.query(text.to_string());
+        if query.query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Send encrypted keys to server
+        let response = self
+            .post(self.url.clone().add("/index/search"), &query.query)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(format!(
+                "collection {} does not exist on the server, run `ebm25 flush` first",
+                self.indexer.collection()
+            )
+            .into());
+        }
+        let response = response.error_for_status()?;
+        let values: Vec<Vec<u8>> = self.read(response).await?;
+
+        let mut ranked = self.indexer.rank(&query, &values);
+        ranked.truncate(top_k);
+        debug!(
+            terms = query.terms.len(),
+            found = values.iter().filter(|v| !v.is_empty()).count(),
+            documents = ranked.len(),
+            "query scored"
+        );
+        Ok(ranked)
+    }
+
+    pub async fn search(&self, text: &str, top_k: usize) -> Result<Vec<SearchResult>> {
+        let ranked = self.rank(text, top_k).await?;
+        let ids: Vec<u64> = ranked.iter().map(|(id, _)| *id).collect();
+        let mut documents: HashMap<u64, Document> = HashMap::new();
+        for (id, document) in self.fetch_documents(&ids).await? {
+            match self.indexer.decrypt(id, &document) {
+                Ok(d) => {
+                    documents.insert(id, d);
+                }
+                Err(e) => warn!(id, error = %e, "document rejected"),
+            }
+        }
+
+        Ok(ranked
+            .into_iter()
+            .filter_map(|(id, score)| {
+                documents
+                    .remove(&id)
+                    .map(|document| SearchResult { document, score })
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,34 @@
+use crate::state::Result;
+use ebm25::{read_text_dir, read_text_file, CorpusDocument, JsonFields, JsonLinesReader};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+fn is_json_lines(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("jsonl" | "ndjson")
+    )
+}
+
+// Reads documents from JSONL files, text and Markdown files and
+// directories of them. Files given explicitly are read as text
+// unless they are JSONL, whatever their extension is
+pub fn read_paths(paths: &[impl AsRef<Path>], fields: &JsonFields) -> Result<Vec<CorpusDocument>> {
+    let mut documents = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            documents.extend(read_text_dir(path)?);
+        } else if is_json_lines(path) {
+            let file =
+                File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            for document in JsonLinesReader::new(BufReader::new(file), fields.clone()) {
+                documents.push(document.map_err(|e| format!("{}: {}", path.display(), e))?);
+            }
+        } else {
+            documents.push(read_text_file(path)?);
+        }
+    }
+    Ok(documents)
+}
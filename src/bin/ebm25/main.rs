@@ -0,0 +1,539 @@
+mod client;
+mod input;
+mod state;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use client::Client;
+use ebm25::{
+    read_queries, write_json_lines, CipherAlgorithm, Compression, DocumentLength, Evaluation,
+    Indexer, JsonFields, MasterKey, Qrels, WireFormat,
+};
+use state::{new_indexer, ClientState, KeySource, Result, Settings, StateDir};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(name = "ebm25", about = "Client of the encrypted BM25 search server")]
+struct Cli {
+    /// Directory with the key, settings and local index
+    #[arg(long, env = "EBM25_STATE_DIR", default_value = ".ebm25", global = true)]
+    state_dir: PathBuf,
+
+    /// API token, overrides the token stored by `init`
+    #[arg(long, env = "EBM25_TOKEN", hide_env_values = true, global = true)]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Generate a master key and store the settings of the collection
+    Init {
+        /// URL of the server
+        #[arg(long, env = "EBM25_URL", default_value = "http://localhost:8080")]
+        url: String,
+
+        /// Collection on the server
+        #[arg(long, env = "EBM25_COLLECTION", default_value = ebm25::DEFAULT_COLLECTION)]
+        collection: String,
+
+        #[arg(long, value_enum, default_value_t = Format::Binary)]
+        wire_format: Format,
+
+        #[arg(long, value_enum, default_value_t = Cipher::Aes256Gcm)]
+        cipher: Cipher,
+
+        /// Compress documents before encryption
+        #[arg(long)]
+        compress: bool,
+
+        /// Unit of document lengths for BM25 length normalization
+        #[arg(long, value_enum, default_value_t = Length::Tokens)]
+        document_length: Length,
+
+        /// Derive the master key from a passphrase instead of storing it,
+        /// the passphrase is read from EBM25_PASSPHRASE or prompted for
+        #[arg(long)]
+        passphrase: bool,
+
+        /// Store the token in the settings
+        #[arg(long)]
+        save_token: bool,
+    },
+    /// Index text, Markdown and JSONL files, directories are read recursively
+    Add {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        #[command(flatten)]
+        fields: FieldArgs,
+    },
+    /// Upload new documents and the index to the server
+    Flush,
+    /// Search the collection
+    Search {
+        query: String,
+
+        /// Number of results
+        #[arg(long, short = 'k', default_value_t = 10)]
+        top_k: usize,
+    },
+    /// Print a document stored on the server
+    Get { id: u64 },
+    /// Remove a document locally and from the server
+    Delete { id: u64 },
+    /// Print statistics of the collection
+    Stats,
+    /// Re-encrypt documents and the index under new keys
+    Rotate,
+    /// Run a query set and report nDCG@k, MAP, MRR and recall@k
+    Evaluate {
+        /// TREC qrels file: query-id iteration document-id relevance
+        #[arg(long)]
+        qrels: PathBuf,
+
+        /// Queries, one `query-id<TAB>text` per line
+        #[arg(long)]
+        queries: PathBuf,
+
+        /// Cut off of nDCG and recall
+        #[arg(short, default_value_t = 10)]
+        k: usize,
+
+        /// Number of documents retrieved per query, MAP and MRR use all of them
+        #[arg(long, default_value_t = 100)]
+        depth: usize,
+
+        /// What qrels document ids refer to, titles for collections
+        /// imported with the document id as title (`--title-field`)
+        #[arg(long, value_enum, default_value_t = DocId::Id)]
+        docid: DocId,
+
+        /// Print the metrics of every query
+        #[arg(long)]
+        per_query: bool,
+    },
+    /// Decrypt documents stored on the server and write them as JSONL
+    Export {
+        /// File to write to [default: stdout]
+        #[arg(long, short = 'o')]
+        output: Option<PathBuf>,
+
+        #[command(flatten)]
+        fields: FieldArgs,
+    },
+}
+
+// Field names of JSONL documents
+#[derive(clap::Args, Debug)]
+struct FieldArgs {
+    /// Field with the numeric id of a document, documents without it get a random id
+    #[arg(long, default_value = "id")]
+    id_field: String,
+
+    #[arg(long, default_value = "title")]
+    title_field: String,
+
+    #[arg(long, default_value = "body")]
+    body_field: String,
+}
+
+impl From<&FieldArgs> for JsonFields {
+    fn from(args: &FieldArgs) -> Self {
+        JsonFields {
+            id: args.id_field.clone(),
+            title: args.title_field.clone(),
+            body: args.body_field.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    Binary,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DocId {
+    Id,
+    Title,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Length {
+    Tokens,
+    Bytes,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Cipher {
+    Aes256Gcm,
+    Aes256GcmSiv,
+    Xchacha20Poly1305,
+}
+
+impl From<Format> for WireFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Binary => WireFormat::Binary,
+            Format::Json => WireFormat::Json,
+        }
+    }
+}
+
+impl From<Length> for DocumentLength {
+    fn from(length: Length) -> Self {
+        match length {
+            Length::Tokens => DocumentLength::Tokens,
+            Length::Bytes => DocumentLength::Bytes,
+        }
+    }
+}
+
+impl From<Cipher> for CipherAlgorithm {
+    fn from(cipher: Cipher) -> Self {
+        match cipher {
+            Cipher::Aes256Gcm => CipherAlgorithm::Aes256Gcm,
+            Cipher::Aes256GcmSiv => CipherAlgorithm::Aes256GcmSiv,
+            Cipher::Xchacha20Poly1305 => CipherAlgorithm::XChaCha20Poly1305,
+        }
+    }
+}
+
+fn read_passphrase(prompt: &str) -> Result<String> {
+    match std::env::var("EBM25_PASSPHRASE") {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => Ok(rpassword::prompt_password(prompt)?),
+    }
+}
+
+fn master_key(source: KeySource) -> Result<MasterKey> {
+    match source {
+        KeySource::Random(key) => Ok(key),
+        KeySource::Passphrase(params) => Ok(MasterKey::unlock(
+            &read_passphrase("Passphrase: ")?,
+            &params,
+        )?),
+    }
+}
+
+fn init(cli: &Cli, dir: &StateDir) -> Result<()> {
+    let Command::Init {
+        url,
+        collection,
+        wire_format,
+        cipher,
+        compress,
+        document_length,
+        passphrase,
+        save_token,
+    } = &cli.command
+    else {
+        unreachable!()
+    };
+
+    let settings = Settings {
+        url: url.clone(),
+        collection: collection.clone(),
+        wire_format: (*wire_format).into(),
+        token: if *save_token { cli.token.clone() } else { None },
+    };
+    let (master_key, source) = if *passphrase {
+        let passphrase = read_passphrase("New passphrase: ")?;
+        let (master_key, params) = MasterKey::from_passphrase(&passphrase)?;
+        (master_key, KeySource::Passphrase(params))
+    } else {
+        let master_key = MasterKey::generate();
+        (master_key.clone(), KeySource::Random(master_key))
+    };
+    let compression = if *compress {
+        Compression::Zstd
+    } else {
+        Compression::None
+    };
+    let indexer = new_indexer(
+        &settings,
+        master_key,
+        (*cipher).into(),
+        compression,
+        (*document_length).into(),
+    )?;
+
+    dir.init(&settings, &source, &indexer)?;
+    println!("Initialized {}", dir.path().display());
+    Ok(())
+}
+
+// Restores the client from the state directory
+fn open(cli: &Cli, dir: &StateDir) -> Result<Client> {
+    let settings = dir.settings()?;
+    let token = cli
+        .token
+        .clone()
+        .or(settings.token.clone())
+        .ok_or("no API token, set EBM25_TOKEN or pass --token")?;
+    let master_key = master_key(dir.key_source()?)?;
+    let ClientState {
+        indexer,
+        pending,
+        staged,
+    } = dir.load_state()?;
+    Client::new(
+        &settings.url,
+        &token,
+        settings.wire_format,
+        Indexer::restore(master_key, indexer),
+        pending,
+        staged,
+    )
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let dir = StateDir::new(cli.state_dir.clone());
+    if let Command::Init { .. } = cli.command {
+        return init(&cli, &dir);
+    }
+
+    let mut client = open(&cli, &dir)?;
+    // a key rotation that was interrupted is finished before anything
+    // else is sent, the client already uses the keys of the new epoch
+    let resumed = match cli.command {
+        Command::Add { .. } => None,
+        _ => client.finish_rotation().await?,
+    };
+    if let Some(epoch) = resumed {
+        dir.save_state(&client.state())?;
+        eprintln!("Finished the interrupted key rotation to epoch {}", epoch);
+    }
+
+    match &cli.command {
+        Command::Init { .. } => unreachable!(),
+        Command::Add { paths, fields } => {
+            let documents = input::read_paths(paths, &fields.into())?;
+            // nothing is added if any of the documents is rejected
+            let titles: Vec<String> = documents.iter().map(|d| d.title.clone()).collect();
+            let added = client.import(documents)?;
+            for (id, title) in added.iter().zip(titles) {
+                println!("{}\t{}", id, title);
+            }
+            dir.save_state(&client.state())?;
+            println!(
+                "Added {} documents, {} pending flush",
+                added.len(),
+                client.pending()
+            );
+        }
+        Command::Flush => {
+            let result = client.flush().await;
+            // documents that were uploaded are recorded even if the index upload failed
+            dir.save_state(&client.state())?;
+            let report = result?;
+            println!(
+                "Uploaded {} documents and {} index entries",
+                report.documents, report.index.records
+            );
+        }
+        Command::Search { query, top_k } => {
+            let results = client.search(query, *top_k).await?;
+            if results.is_empty() {
+                println!("No results");
+            }
+            for (rank, result) in results.iter().enumerate() {
+                println!(
+                    "{:>3}. {:>8.4}  {}  {}",
+                    rank + 1,
+                    result.score,
+                    result.document.id,
+                    result.document.title
+                );
+            }
+        }
+        Command::Get { id } => match client.get(*id).await? {
+            Some(document) => {
+                if !document.title.is_empty() {
+                    println!("# {}\n", document.title);
+                }
+                println!("{}", document.content);
+            }
+            None => return Err(format!("document {} not found", id).into()),
+        },
+        Command::Delete { id } => {
+            let result = client.delete(*id).await;
+            dir.save_state(&client.state())?;
+            if !result? {
+                return Err(format!("document {} not found", id).into());
+            }
+            // the server still has postings of the document
+            let report = client.flush().await?;
+            dir.save_state(&client.state())?;
+            println!(
+                "Deleted {}, {} index entries updated",
+                id, report.index.records
+            );
+        }
+        Command::Stats => {
+            let stats = client.stats().await?;
+            println!("collection      {}", stats.name);
+            println!("key epoch       {}", stats.epoch);
+            println!("documents       {}", stats.documents);
+            println!("index entries   {}", stats.index_entries);
+            println!("document bytes  {}", stats.document_bytes);
+            println!("index bytes     {}", stats.index_bytes);
+            println!("local documents {}", client.indexer().len());
+            println!("pending flush   {}", client.pending());
+            println!(
+                "terms           {}",
+                client.indexer().dictionary.terms.len()
+            );
+        }
+        Command::Rotate if resumed.is_some() => {}
+        Command::Rotate => {
+            // the new epoch is saved before anything is staged, keys of
+            // earlier epochs are derived again, so documents stay readable
+            // and a failed rotation is finished by the next run
+            let result = client.start_rotation().await;
+            dir.save_state(&client.state())?;
+            let epoch = result?;
+            client.finish_rotation().await?;
+            dir.save_state(&client.state())?;
+            println!("Rotated keys to epoch {}", epoch);
+        }
+        Command::Evaluate {
+            qrels,
+            queries,
+            k,
+            depth,
+            docid,
+            per_query,
+        } => {
+            let open = |path: &PathBuf| -> Result<BufReader<File>> {
+                let file = File::open(path)
+                    .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                Ok(BufReader::new(file))
+            };
+            let qrels = Qrels::read(open(qrels)?)?;
+            let queries = read_queries(open(queries)?)?;
+            let depth = (*depth).max(*k);
+
+            let mut evaluation = Evaluation::new(*k);
+            for (id, text) in queries.iter() {
+                if qrels.judgments(id).is_none() {
+                    evaluation.add(id, &[], &qrels);
+                    continue;
+                }
+                let ranked: Vec<String> = match docid {
+                    DocId::Id => client
+                        .rank(text, depth)
+                        .await?
+                        .into_iter()
+                        .map(|(id, _)| id.to_string())
+                        .collect(),
+                    DocId::Title => client
+                        .search(text, depth)
+                        .await?
+                        .into_iter()
+                        .map(|result| result.document.title)
+                        .collect(),
+                };
+                evaluation.add(id, &ranked, &qrels);
+            }
+
+            if *per_query {
+                println!("query\tnDCG@{}\tAP\tRR\trecall@{}", k, k);
+                for (id, metrics) in evaluation.queries() {
+                    println!(
+                        "{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}",
+                        id,
+                        metrics.ndcg,
+                        metrics.average_precision,
+                        metrics.reciprocal_rank,
+                        metrics.recall
+                    );
+                }
+            }
+            println!(
+                "queries    {} ({} without relevant documents skipped)",
+                evaluation.queries().len(),
+                evaluation.unjudged()
+            );
+            println!("nDCG@{:<5} {:.4}", k, evaluation.ndcg());
+            println!("MAP        {:.4}", evaluation.map());
+            println!("MRR        {:.4}", evaluation.mrr());
+            println!("recall@{:<3} {:.4}", k, evaluation.recall());
+        }
+        Command::Export { output, fields } => {
+            let documents = client.export().await?;
+            let count = match output {
+                Some(path) => write_json_lines(
+                    BufWriter::new(File::create(path)?),
+                    documents,
+                    &fields.into(),
+                )?,
+                None => write_json_lines(std::io::stdout().lock(), documents, &fields.into())?,
+            };
+            if client.pending() > 0 {
+                eprintln!(
+                    "{} documents are not flushed and were not exported",
+                    client.pending()
+                );
+            }
+            eprintln!("Exported {} documents", count);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    // logs go to stderr, EBM25_LOG=debug shows statistics of queries
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_env("EBM25_LOG").unwrap_or_else(|_| "warn".into()))
+        .with_writer(std::io::stderr)
+        .init();
+
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let cli = Cli::try_parse_from(["ebm25", "search", "brown fox", "-k", "3"]).unwrap();
+        assert_eq!(cli.state_dir, PathBuf::from(".ebm25"));
+        match cli.command {
+            Command::Search { query, top_k } => {
+                assert_eq!(query, "brown fox");
+                assert_eq!(top_k, 3);
+            }
+            command => panic!("unexpected {:?}", command),
+        }
+
+        assert!(Cli::try_parse_from(["ebm25", "add"]).is_err());
+        let cli = Cli::try_parse_from([
+            "ebm25",
+            "init",
+            "--cipher",
+            "xchacha20-poly1305",
+            "--compress",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Init {
+                cipher: Cipher::Xchacha20Poly1305,
+                compress: true,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use ebm25::{
+    CipherAlgorithm, Compression, DocumentLength, EncryptedDocument, Indexer, IndexerState,
+    KeyEpoch, MasterKey, PassphraseParams, WireFormat, DEFAULT_COLLECTION,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const CONFIG_FILE: &str = "config.toml";
+const MASTER_KEY_FILE: &str = "master.key";
+const PASSPHRASE_FILE: &str = "passphrase.toml";
+const STATE_FILE: &str = "state.bin";
+
+// Settings written by `ebm25 init`, the token can also be
+// given with EBM25_TOKEN so it does not have to be stored
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub url: String,
+    pub collection: String,
+    pub wire_format: WireFormat,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:8080".to_string(),
+            collection: DEFAULT_COLLECTION.to_string(),
+            wire_format: WireFormat::Binary,
+            token: None,
+        }
+    }
+}
+
+// Postings of the collection along with documents that are
+// encrypted but not uploaded yet
+#[derive(Serialize, Deserialize)]
+pub struct ClientState {
+    pub indexer: IndexerState,
+    pub pending: Vec<EncryptedDocument>,
+    // key epoch of a rotation that is not committed yet, it is
+    // finished by the next command that talks to the server
+    pub staged: Option<KeyEpoch>,
+}
+
+// How the master key is kept: a random key in a file readable only by
+// the owner, or Argon2id parameters to derive it from a passphrase
+pub enum KeySource {
+    Random(MasterKey),
+    Passphrase(PassphraseParams),
+}
+
+// Directory with the settings, the key and the state of the client
+pub struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.join(CONFIG_FILE).exists()
+    }
+
+    pub fn init(&self, settings: &Settings, key: &KeySource, indexer: &Indexer) -> Result<()> {
+        if self.exists() {
+            return Err(format!("{} is already initialized", self.path.display()).into());
+        }
+        fs::create_dir_all(&self.path)?;
+
+        match key {
+            KeySource::Random(master_key) => {
+                self.write_private(MASTER_KEY_FILE, master_key.as_bytes())?
+            }
+            KeySource::Passphrase(params) => {
+                self.write(PASSPHRASE_FILE, toml::to_string(params)?.as_bytes())?
+            }
+        }
+        self.save_state(&ClientState {
+            indexer: indexer.snapshot(),
+            pending: Vec::new(),
+            staged: None,
+        })?;
+        // written last, a failed init can be repeated
+        self.write_private(CONFIG_FILE, toml::to_string(settings)?.as_bytes())
+    }
+
+    pub fn settings(&self) -> Result<Settings> {
+        let text = fs::read_to_string(self.path.join(CONFIG_FILE)).map_err(|e| {
+            format!(
+                "cannot read {}, run `ebm25 init` first: {}",
+                self.path.join(CONFIG_FILE).display(),
+                e
+            )
+        })?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    pub fn key_source(&self) -> Result<KeySource> {
+        let passphrase = self.path.join(PASSPHRASE_FILE);
+        if passphrase.exists() {
+            let params = toml::from_str(&fs::read_to_string(passphrase)?)?;
+            return Ok(KeySource::Passphrase(params));
+        }
+
+        let bytes = fs::read(self.path.join(MASTER_KEY_FILE))?;
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| format!("{} is not a 256 bit key", MASTER_KEY_FILE))?;
+        Ok(KeySource::Random(MasterKey::from_bytes(key)))
+    }
+
+    pub fn load_state(&self) -> Result<ClientState> {
+        let bytes = fs::read(self.path.join(STATE_FILE))?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+
+    pub fn save_state(&self, state: &ClientState) -> Result<()> {
+        self.write_private(STATE_FILE, &bincode::serialize(state)?)
+    }
+
+    fn write(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        self.write_with_mode(name, bytes, 0o644)
+    }
+
+    // Terms of the dictionary and postings are plaintext
+    fn write_private(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        self.write_with_mode(name, bytes, 0o600)
+    }
+
+    // Writes to a temporary file that replaces the target, an
+    // interrupted write never leaves a truncated state behind
+    fn write_with_mode(&self, name: &str, bytes: &[u8], mode: u32) -> Result<()> {
+        let target = self.path.join(name);
+        let tmp = self.path.join(format!("{}.tmp", name));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(mode);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        let mut file = options.open(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(tmp, target)?;
+        Ok(())
+    }
+}
+
+// Cipher, compression and the unit of document lengths are fixed at
+// init, documents of a collection are always indexed the same way
+pub fn new_indexer(
+    settings: &Settings,
+    master_key: MasterKey,
+    cipher: CipherAlgorithm,
+    compression: Compression,
+    document_length: DocumentLength,
+) -> Result<Indexer> {
+    let mut indexer = Indexer::with_collection(&settings.collection, master_key);
+    indexer.set_cipher(cipher);
+    indexer.set_compression(compression);
+    indexer.set_document_length(document_length)?;
+    Ok(indexer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ebm25-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn test_init_and_reload() {
+        let path = temp_dir("state");
+        let dir = StateDir::new(path.clone());
+        let settings = Settings {
+            collection: "notes".to_string(),
+            ..Settings::default()
+        };
+        let master_key = MasterKey::generate();
+        let mut indexer = new_indexer(
+            &settings,
+            master_key.clone(),
+            CipherAlgorithm::XChaCha20Poly1305,
+            Compression::Zstd,
+            DocumentLength::Tokens,
+        )
+        .unwrap();
+        dir.init(&settings, &KeySource::Random(master_key.clone()), &indexer)
+            .unwrap();
+        assert!(dir
+            .init(&settings, &KeySource::Random(master_key.clone()), &indexer)
+            .is_err());
+
+        let document = indexer.add_document("title".to_string(), "brown fox".to_string());
+        dir.save_state(&ClientState {
+            indexer: indexer.snapshot(),
+            pending: vec![document.clone()],
+            staged: Some(1),
+        })
+        .unwrap();
+
+        assert_eq!(dir.settings().unwrap(), settings);
+        match dir.key_source().unwrap() {
+            KeySource::Random(key) => assert!(key == master_key),
+            KeySource::Passphrase(_) => panic!("random key expected"),
+        }
+        let state = dir.load_state().unwrap();
+        assert_eq!(state.pending, vec![document.clone()]);
+        assert_eq!(state.staged, Some(1));
+        let restored = Indexer::restore(master_key, state.indexer);
+        assert_eq!(restored.collection(), "notes");
+        assert_eq!(restored.document_ids(), vec![document.id]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path.join(MASTER_KEY_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+use clap::{Parser, ValueEnum};
+use ebm25::server::{is_valid_name, TenantConfig};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 8080;
+
+// Same limit actix applies to JSON bodies by default
+const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+// Log filter in the `RUST_LOG` syntax
+const DEFAULT_LOG: &str = "info";
+
+// Tenant that owns the token given with --token
+const DEFAULT_TENANT: &str = "default";
+
+// Shorter tokens are rejected, they could be guessed
+const MIN_TOKEN_LENGTH: usize = 16;
+
+// Every flag can also be set with an environment variable, settings
+// that are set neither way are taken from the config file and then
+// from the defaults
+#[derive(Parser, Debug, Default)]
+#[command(name = "server", about = "Encrypted BM25 search server")]
+pub struct Args {
+    /// TOML file with server settings
+    #[arg(long, env = "EBM25_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long, env = "EBM25_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Port to listen on [default: 8080]
+    #[arg(long, env = "EBM25_PORT")]
+    pub port: Option<u16>,
+
+    /// Number of worker threads [default: number of CPUs]
+    #[arg(long, env = "EBM25_WORKERS")]
+    pub workers: Option<usize>,
+
+    /// Maximum size of a request body in bytes, streamed index uploads are not limited [default: 2 MiB]
+    #[arg(long, env = "EBM25_MAX_BODY_SIZE")]
+    pub max_body_size: Option<usize>,
+
+    /// PEM file with the certificate chain, enables TLS
+    #[arg(long, env = "EBM25_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate
+    #[arg(long, env = "EBM25_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// API token of the `default` tenant, more tenants are configured in the config file
+    #[arg(long, env = "EBM25_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Token that grants access to /metrics and /stats, they are not served without it
+    #[arg(long, env = "EBM25_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// Log filter, e.g. `info` or `warn,server=debug` [default: info]
+    #[arg(long, env = "EBM25_LOG")]
+    pub log: Option<String>,
+
+    /// Format of log lines [default: text]
+    #[arg(long, env = "EBM25_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // one JSON object per line, for log collectors
+    Json,
+}
+
+// Layout of the config file:
+//
+//   listen = "0.0.0.0"
+//   port = 8443
+//   workers = 4
+//   max_body_size = 4194304
+//   log = "info"
+//   log_format = "json"
+//   admin_token = "..."
+//
+//   [tls]
+//   cert = "/etc/ebm25/cert.pem"
+//   key = "/etc/ebm25/key.pem"
+//
+//   [[tenants]]
+//   name = "search"
+//   token = "..."
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub listen: Option<String>,
+    pub port: Option<u16>,
+    pub workers: Option<usize>,
+    pub max_body_size: Option<usize>,
+    pub log: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub tls: Option<TlsConfig>,
+    pub admin_token: Option<AdminToken>,
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+}
+
+// Token of the operator, it is never printed
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct AdminToken(pub String);
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub listen: String,
+    pub port: u16,
+    // None keeps the actix default of one worker per CPU
+    pub workers: Option<usize>,
+    pub max_body_size: usize,
+    pub log: String,
+    pub log_format: LogFormat,
+    pub tls: Option<TlsConfig>,
+    pub admin_token: Option<AdminToken>,
+    pub tenants: Vec<TenantConfig>,
+}
+
+impl Config {
+    pub fn load() -> io::Result<Self> {
+        let args = Args::parse();
+        let file = match &args.config {
+            Some(path) => FileConfig::read(path)?,
+            None => FileConfig::default(),
+        };
+        let config = Self::merge(args, file);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn merge(args: Args, file: FileConfig) -> Self {
+        let tls = match (args.tls_cert, args.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            _ => file.tls,
+        };
+        let mut tenants = file.tenants;
+        if let Some(token) = args.token {
+            tenants.retain(|t| t.name != DEFAULT_TENANT);
+            tenants.push(TenantConfig {
+                name: DEFAULT_TENANT.to_string(),
+                token,
+            });
+        }
+        Self {
+            listen: args
+                .listen
+                .or(file.listen)
+                .unwrap_or_else(|| DEFAULT_LISTEN.to_string()),
+            port: args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            workers: args.workers.or(file.workers),
+            max_body_size: args
+                .max_body_size
+                .or(file.max_body_size)
+                .unwrap_or(DEFAULT_MAX_BODY_SIZE),
+            log: args
+                .log
+                .or(file.log)
+                .unwrap_or_else(|| DEFAULT_LOG.to_string()),
+            log_format: args.log_format.or(file.log_format).unwrap_or_default(),
+            tls,
+            admin_token: args.admin_token.map(AdminToken).or(file.admin_token),
+            tenants,
+        }
+    }
+
+    // The server does not start without authentication, every tenant
+    // needs a unique name usable in paths and a unique token, the admin
+    // token must not be one of them
+    fn validate(&self) -> io::Result<()> {
+        let error = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if self.tenants.is_empty() {
+            return Err(error(
+                "no API tokens configured, set --token or add [[tenants]] to the config file"
+                    .to_string(),
+            ));
+        }
+
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        for tenant in &self.tenants {
+            if !is_valid_name(&tenant.name) {
+                return Err(error(format!("invalid tenant name {:?}", tenant.name)));
+            }
+            if !names.insert(&tenant.name) {
+                return Err(error(format!("duplicate tenant {:?}", tenant.name)));
+            }
+            if tenant.token.len() < MIN_TOKEN_LENGTH {
+                return Err(error(format!(
+                    "token of tenant {:?} is shorter than {} characters",
+                    tenant.name, MIN_TOKEN_LENGTH
+                )));
+            }
+            if !tokens.insert(&tenant.token) {
+                return Err(error(format!(
+                    "token of tenant {:?} is used by another tenant",
+                    tenant.name
+                )));
+            }
+        }
+
+        if let Some(AdminToken(token)) = &self.admin_token {
+            if token.len() < MIN_TOKEN_LENGTH {
+                return Err(error(format!(
+                    "admin token is shorter than {} characters",
+                    MIN_TOKEN_LENGTH
+                )));
+            }
+            if tokens.contains(token) {
+                return Err(error("admin token is used by a tenant".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn address(&self) -> (String, u16) {
+        (self.listen.clone(), self.port)
+    }
+}
+
+impl FileConfig {
+    fn read(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+}
+
+impl TlsConfig {
+    pub fn server_config(&self) -> io::Result<rustls::ServerConfig> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))
+            .collect::<Result<Vec<CertificateDer>, _>>()?;
+        if certs.is_empty() {
+            return Err(invalid(&self.cert, "no certificates found"));
+        }
+        let key: PrivateKeyDer =
+            rustls_pemfile::private_key(&mut BufReader::new(File::open(&self.key)?))?
+                .ok_or_else(|| invalid(&self.key, "no private key found"))?;
+
+        rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(&self.cert, &e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(&self.key, &e.to_string()))
+    }
+}
+
+fn invalid(path: &Path, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", path.display(), message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            listen = "0.0.0.0"
+            port = 9000
+            workers = 2
+            log_format = "json"
+
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+            "#,
+        )
+        .unwrap();
+        let args = Args {
+            port: Some(9443),
+            ..Args::default()
+        };
+
+        let config = Config::merge(args, file);
+        assert_eq!(config.listen, "0.0.0.0");
+        assert_eq!(config.port, 9443);
+        assert_eq!(config.workers, Some(2));
+        assert_eq!(config.max_body_size, DEFAULT_MAX_BODY_SIZE);
+        assert_eq!(config.log, "info");
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.tls.unwrap().cert, PathBuf::from("cert.pem"));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::merge(Args::default(), FileConfig::default());
+        assert_eq!(config.address(), ("127.0.0.1".to_string(), 8080));
+        assert_eq!(config.workers, None);
+        assert_eq!(config.tls, None);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_tenants() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            [[tenants]]
+            name = "search"
+            token = "search-team-token-1"
+
+            [[tenants]]
+            name = "default"
+            token = "replaced-by-the-flag"
+            "#,
+        )
+        .unwrap();
+        let args = Args {
+            token: Some("default-token-0001".to_string()),
+            ..Args::default()
+        };
+
+        let config = Config::merge(args, file);
+        assert!(config.validate().is_ok());
+        let names: Vec<&str> = config.tenants.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["search", "default"]);
+        assert!(!format!("{:?}", config).contains("default-token-0001"));
+
+        let mut invalid = config.tenants.clone();
+        invalid[1].token = invalid[0].token.clone();
+        let shared_token = Config {
+            tenants: invalid,
+            ..config
+        };
+        assert!(shared_token.validate().is_err());
+
+        let short_token = Config::merge(
+            Args {
+                token: Some("short".to_string()),
+                ..Args::default()
+            },
+            FileConfig::default(),
+        );
+        assert!(short_token.validate().is_err());
+    }
+
+    #[test]
+    fn test_admin_token() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            admin_token = "operator-token-0001"
+
+            [[tenants]]
+            name = "search"
+            token = "search-team-token-1"
+            "#,
+        )
+        .unwrap();
+        let config = Config::merge(Args::default(), file);
+        assert!(config.validate().is_ok());
+        assert!(!format!("{:?}", config).contains("operator-token-0001"));
+
+        let tenant_token = Config {
+            admin_token: Some(AdminToken(config.tenants[0].token.clone())),
+            ..config
+        };
+        assert!(tenant_token.validate().is_err());
+
+        let short_token = Config {
+            admin_token: Some(AdminToken("short".to_string())),
+            ..tenant_token
+        };
+        assert!(short_token.validate().is_err());
+    }
+
+    #[test]
+    fn test_unknown_file_keys() {
+        assert!(toml::from_str::<FileConfig>("prot = 80").is_err());
+    }
+}
@@ -0,0 +1,57 @@
+mod config;
+
+use actix_web::{web, HttpServer};
+use config::{AdminToken, Config, LogFormat};
+use ebm25::server::{app, Metrics, ServerState};
+use tracing::{debug, info};
+use tracing_subscriber::EnvFilter;
+
+fn init_logging(config: &Config) -> std::io::Result<()> {
+    let filter = EnvFilter::try_new(&config.log).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid log filter {:?}: {}", config.log, e),
+        )
+    })?;
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Text => logs.init(),
+        LogFormat::Json => logs.json().init(),
+    }
+    Ok(())
+}
+
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    let config = Config::load()?;
+    init_logging(&config)?;
+    debug!(?config, "configuration loaded");
+    let max_body_size = config.max_body_size;
+
+    let mut state = ServerState::new(&config.tenants);
+    if let Some(AdminToken(token)) = &config.admin_token {
+        state = state.with_admin_token(token);
+    }
+    let shared_data = web::Data::new(state);
+    let metrics = web::Data::new(Metrics::new());
+
+    let mut server =
+        HttpServer::new(move || app(shared_data.clone(), metrics.clone(), max_body_size));
+
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    server = match &config.tls {
+        Some(tls) => server.bind_rustls_0_23(config.address(), tls.server_config()?)?,
+        None => server.bind(config.address())?,
+    };
+
+    info!(
+        address = %config.listen,
+        port = config.port,
+        tls = config.tls.is_some(),
+        tenants = config.tenants.len(),
+        "listening"
+    );
+    server.run().await
+}
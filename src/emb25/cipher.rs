@@ -0,0 +1,129 @@
+use crate::emb25::error::Error;
+use aes_gcm::aead::generic_array::typenum::Unsigned;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+
+// AEAD used to encrypt documents, the id is stored along with
+// the ciphertext so documents encrypted with different
+// algorithms can live in the same storage
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CipherAlgorithm {
+    // random 96-bit nonces, should not be used for more
+    // than 2^32 documents under the same key
+    #[default]
+    Aes256Gcm,
+    // nonce misuse resistant, the price is a second pass over the plaintext
+    Aes256GcmSiv,
+    // 192-bit nonces are safe to generate randomly for any number of documents
+    XChaCha20Poly1305,
+}
+
+// Output of the AEAD: the nonce and the ciphertext with the tag
+pub struct Sealed {
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl CipherAlgorithm {
+    pub fn encrypt(&self, key: &[u8], plaintext: &[u8], aad: &[u8]) -> Sealed {
+        match self {
+            CipherAlgorithm::Aes256Gcm => seal::<Aes256Gcm>(key, plaintext, aad),
+            CipherAlgorithm::Aes256GcmSiv => seal::<Aes256GcmSiv>(key, plaintext, aad),
+            CipherAlgorithm::XChaCha20Poly1305 => seal::<XChaCha20Poly1305>(key, plaintext, aad),
+        }
+    }
+
+    pub fn decrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            CipherAlgorithm::Aes256Gcm => open::<Aes256Gcm>(key, nonce, ciphertext, aad),
+            CipherAlgorithm::Aes256GcmSiv => open::<Aes256GcmSiv>(key, nonce, ciphertext, aad),
+            CipherAlgorithm::XChaCha20Poly1305 => {
+                open::<XChaCha20Poly1305>(key, nonce, ciphertext, aad)
+            }
+        }
+    }
+}
+
+fn seal<C: Aead + AeadCore + KeyInit>(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Sealed {
+    let cipher = C::new_from_slice(key).expect("document key is 256 bit");
+    let nonce = C::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("plaintext fits into a single AEAD message");
+
+    Sealed {
+        nonce: nonce.to_vec(),
+        ciphertext,
+    }
+}
+
+fn open<C: Aead + AeadCore + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, Error> {
+    // nonce comes from the server, so its length is not trusted
+    if nonce.len() != C::NonceSize::to_usize() {
+        return Err(Error::Decryption);
+    }
+    let cipher = C::new_from_slice(key).expect("document key is 256 bit");
+    cipher
+        .decrypt(
+            GenericArray::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::Decryption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [CipherAlgorithm; 3] = [
+        CipherAlgorithm::Aes256Gcm,
+        CipherAlgorithm::Aes256GcmSiv,
+        CipherAlgorithm::XChaCha20Poly1305,
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        let key = [1u8; 32];
+        for algorithm in ALGORITHMS {
+            let sealed = algorithm.encrypt(&key, b"plaintext", b"aad");
+            let opened = algorithm.decrypt(&key, &sealed.nonce, &sealed.ciphertext, b"aad");
+            assert_eq!(opened.unwrap(), b"plaintext");
+        }
+    }
+
+    #[test]
+    fn test_wrong_aad_or_nonce() {
+        let key = [1u8; 32];
+        for algorithm in ALGORITHMS {
+            let sealed = algorithm.encrypt(&key, b"plaintext", b"aad");
+            let wrong_aad = algorithm.decrypt(&key, &sealed.nonce, &sealed.ciphertext, b"other");
+            assert_eq!(wrong_aad, Err(Error::Decryption));
+            let short_nonce = algorithm.decrypt(&key, &[0u8; 4], &sealed.ciphertext, b"aad");
+            assert_eq!(short_nonce, Err(Error::Decryption));
+        }
+    }
+}
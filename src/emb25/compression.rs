@@ -0,0 +1,104 @@
+use crate::emb25::error::Error;
+use serde::{Deserialize, Serialize};
+
+// Compressed payloads are padded to the next power of two (but not
+// less than this), so the ciphertext size reveals only the magnitude
+// of the compressed size instead of the exact compression ratio
+const MIN_PADDED_SIZE: usize = 256;
+
+const LENGTH_PREFIX: usize = 4;
+
+const ZSTD_LEVEL: i32 = 3;
+
+// Compression applied to the serialized document before encryption
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    pub fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Zstd => {
+                let compressed =
+                    zstd::bulk::compress(bytes, ZSTD_LEVEL).expect("in-memory compression");
+                pad(&compressed)
+            }
+        }
+    }
+
+    pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Zstd => {
+                let compressed = unpad(bytes)?;
+                zstd::stream::decode_all(compressed)
+                    .map_err(|e| Error::MalformedDocument(e.to_string()))
+            }
+        }
+    }
+}
+
+// Layout of padded payload: [length: u32 BE][payload][zeros]
+fn pad(payload: &[u8]) -> Vec<u8> {
+    let size = (payload.len() + LENGTH_PREFIX)
+        .next_power_of_two()
+        .max(MIN_PADDED_SIZE);
+
+    let mut padded = Vec::with_capacity(size);
+    padded.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    padded.extend_from_slice(payload);
+    padded.resize(size, 0);
+    padded
+}
+
+fn unpad(padded: &[u8]) -> Result<&[u8], Error> {
+    if padded.len() < LENGTH_PREFIX {
+        return Err(Error::MalformedDocument(
+            "missing length prefix".to_string(),
+        ));
+    }
+    let (prefix, rest) = padded.split_at(LENGTH_PREFIX);
+    let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+    rest.get(..len)
+        .ok_or_else(|| Error::MalformedDocument("length prefix exceeds payload".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zstd_round_trip() {
+        let text = "the quick brown fox jumps over the lazy dog ".repeat(100);
+        let compressed = Compression::Zstd.compress(text.as_bytes());
+
+        assert!(compressed.len() < text.len());
+        assert!(compressed.len().is_power_of_two());
+        assert_eq!(
+            Compression::Zstd.decompress(&compressed).unwrap(),
+            text.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_padding_hides_ratio() {
+        // both payloads compress to different sizes, but end up in the same bucket
+        let repetitive = "a".repeat(1000);
+        let diverse: String = (0..100).map(|i| format!("{} ", i)).collect();
+
+        assert_eq!(
+            Compression::Zstd.compress(repetitive.as_bytes()).len(),
+            Compression::Zstd.compress(diverse.as_bytes()).len()
+        );
+    }
+
+    #[test]
+    fn test_malformed_padding() {
+        assert!(Compression::Zstd.decompress(&[0, 0]).is_err());
+        assert!(Compression::Zstd.decompress(&[0, 0, 1, 0, 1]).is_err());
+    }
+}
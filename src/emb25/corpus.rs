@@ -0,0 +1,291 @@
+use crate::emb25::error::Error;
+use crate::emb25::index::Document;
+use serde_json::{Map, Value};
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use walkdir::WalkDir;
+
+// Document read by an importer, before it is indexed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorpusDocument {
+    // documents without an id get a random one when indexed
+    pub id: Option<u64>,
+    pub title: String,
+    pub body: String,
+}
+
+// Names of the fields of a JSONL document, used for import and export.
+// Id and title are optional in imported documents, the body is required
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonFields {
+    pub id: String,
+    pub title: String,
+    pub body: String,
+}
+
+impl Default for JsonFields {
+    fn default() -> Self {
+        Self {
+            id: "id".to_string(),
+            title: "title".to_string(),
+            body: "body".to_string(),
+        }
+    }
+}
+
+impl JsonFields {
+    fn parse(&self, line: &str) -> Result<CorpusDocument, String> {
+        let value: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        let Value::Object(mut object) = value else {
+            return Err("not a JSON object".to_string());
+        };
+
+        let id = match object.remove(&self.id) {
+            None | Some(Value::Null) => None,
+            Some(Value::Number(n)) => Some(n.as_u64().ok_or("id is not an unsigned integer")?),
+            Some(Value::String(s)) => Some(
+                s.parse()
+                    .map_err(|_| format!("id {:?} is not an unsigned integer", s))?,
+            ),
+            Some(_) => return Err(format!("field {:?} is not an id", self.id)),
+        };
+        let title = match object.remove(&self.title) {
+            None | Some(Value::Null) => String::new(),
+            Some(value) => string_field(&self.title, value)?,
+        };
+        let body = match object.remove(&self.body) {
+            Some(value) => string_field(&self.body, value)?,
+            None => return Err(format!("field {:?} is missing", self.body)),
+        };
+
+        Ok(CorpusDocument { id, title, body })
+    }
+
+    fn format(&self, document: &Document) -> Value {
+        let mut object = Map::new();
+        object.insert(self.id.clone(), Value::from(document.id));
+        object.insert(self.title.clone(), Value::from(document.title.clone()));
+        object.insert(self.body.clone(), Value::from(document.content.clone()));
+        Value::Object(object)
+    }
+}
+
+fn string_field(name: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(format!("field {:?} is not a string", name)),
+    }
+}
+
+// Reads one document per line of a JSONL (NDJSON) stream, blank lines are
+// skipped. Documents are read lazily, so large corpora are not buffered
+pub struct JsonLinesReader<R> {
+    lines: std::io::Lines<R>,
+    fields: JsonFields,
+    line: usize,
+}
+
+impl<R: BufRead> JsonLinesReader<R> {
+    pub fn new(reader: R, fields: JsonFields) -> Self {
+        Self {
+            lines: reader.lines(),
+            fields,
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JsonLinesReader<R> {
+    type Item = Result<CorpusDocument, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(Error::Io(e.to_string()))),
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(
+                self.fields
+                    .parse(&line)
+                    .map_err(|reason| Error::MalformedRecord {
+                        line: self.line,
+                        reason,
+                    }),
+            );
+        }
+    }
+}
+
+// Writes documents as JSONL with the given field names, so an export
+// can be imported again with the same fields. Returns the number of
+// written documents
+pub fn write_json_lines<W: Write>(
+    mut writer: W,
+    documents: impl IntoIterator<Item = Document>,
+    fields: &JsonFields,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    for document in documents {
+        serde_json::to_writer(&mut writer, &fields.format(&document))
+            .map_err(|e| Error::Io(e.to_string()))?;
+        writer
+            .write_all(b"\n")
+            .map_err(|e| Error::Io(e.to_string()))?;
+        count += 1;
+    }
+    writer.flush().map_err(|e| Error::Io(e.to_string()))?;
+    Ok(count)
+}
+
+fn is_text_file(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .as_deref(),
+        Some("txt" | "text" | "md" | "markdown")
+    )
+}
+
+fn is_markdown(path: &Path) -> bool {
+    matches!(
+        path.extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+            .as_deref(),
+        Some("md" | "markdown")
+    )
+}
+
+// Reads a text file as one document. Markdown documents are titled
+// by their first top level heading, other files by their file name
+pub fn read_text_file(path: &Path) -> Result<CorpusDocument, Error> {
+    let body = fs::read_to_string(path)
+        .map_err(|e| Error::Io(format!("cannot read {}: {}", path.display(), e)))?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let title = if is_markdown(path) {
+        markdown_title(&body).unwrap_or(name)
+    } else {
+        name
+    };
+
+    Ok(CorpusDocument {
+        id: None,
+        title,
+        body,
+    })
+}
+
+// Reads text and Markdown files of a directory and its subdirectories
+// in the order of their paths, files with other extensions are skipped
+pub fn read_text_dir(path: &Path) -> Result<Vec<CorpusDocument>, Error> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(path).sort_by_file_name() {
+        let entry = entry.map_err(|e| Error::Io(e.to_string()))?;
+        if entry.file_type().is_file() && is_text_file(entry.path()) {
+            files.push(entry.into_path());
+        }
+    }
+    files.iter().map(|file| read_text_file(file)).collect()
+}
+
+// Text of the first top level heading
+fn markdown_title(text: &str) -> Option<String> {
+    text.lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|title| title.trim().to_string())
+        .filter(|title| !title.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_json_lines() {
+        let input = "{\"docid\": \"42\", \"headline\": \"Fox\", \"text\": \"brown fox\"}\n\
+                     \n\
+                     {\"text\": \"lazy dog\", \"extra\": 1}\n\
+                     {\"docid\": -1, \"text\": \"hare\"}\n\
+                     {\"headline\": \"no body\"}\n";
+        let fields = JsonFields {
+            id: "docid".to_string(),
+            title: "headline".to_string(),
+            body: "text".to_string(),
+        };
+        let documents: Vec<_> = JsonLinesReader::new(input.as_bytes(), fields).collect();
+
+        assert_eq!(
+            documents[0],
+            Ok(CorpusDocument {
+                id: Some(42),
+                title: "Fox".to_string(),
+                body: "brown fox".to_string(),
+            })
+        );
+        assert_eq!(
+            documents[1],
+            Ok(CorpusDocument {
+                id: None,
+                title: String::new(),
+                body: "lazy dog".to_string(),
+            })
+        );
+        assert!(matches!(
+            documents[2],
+            Err(Error::MalformedRecord { line: 4, .. })
+        ));
+        assert!(matches!(
+            documents[3],
+            Err(Error::MalformedRecord { line: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let documents = vec![
+            Document {
+                id: 7,
+                title: "a".to_string(),
+                content: "brown \"fox\"\nand dog".to_string(),
+            },
+            Document {
+                id: u64::MAX,
+                title: String::new(),
+                content: "hare".to_string(),
+            },
+        ];
+        let mut output = Vec::new();
+        let fields = JsonFields::default();
+        assert_eq!(
+            write_json_lines(&mut output, documents.clone(), &fields).unwrap(),
+            2
+        );
+
+        let imported: Vec<CorpusDocument> = JsonLinesReader::new(output.as_slice(), fields)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for (document, imported) in documents.iter().zip(imported) {
+            assert_eq!(imported.id, Some(document.id));
+            assert_eq!(imported.title, document.title);
+            assert_eq!(imported.body, document.content);
+        }
+    }
+
+    #[test]
+    fn test_markdown_title() {
+        assert_eq!(
+            markdown_title("intro\n## Section\n# Title \ntext"),
+            Some("Title".to_string())
+        );
+        assert_eq!(markdown_title("no heading"), None);
+    }
+}
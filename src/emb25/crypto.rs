@@ -1,31 +1,29 @@
-use crate::emb25::index::{IndexUpdate, Term, Term2Document};
+use crate::emb25::cipher::CipherAlgorithm;
+use crate::emb25::compression::Compression;
+use crate::emb25::error::Error;
+use crate::emb25::index::Term;
 use crate::Document;
-use aes_gcm::aead::consts::U12;
-use aes_gcm::aes::Aes256;
-use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, Nonce, OsRng},
-    Aes256Gcm, AesGcm, Key,
-};
-use hex_literal::hex;
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use sha3::digest::core_api::CoreWrapper;
-use sha3::digest::{DynDigest, Update};
-use sha3::{Digest, Sha3_256, Sha3_256Core};
+use sha3::Sha3_256;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub struct SymmetricKey {
-    key: Key<Aes256Gcm>,
+    key: [u8; 32],
 }
 
 // Struct to store the metadata of a document
 // id: is the id of the document in the database
-// size: is the size of the document in bytes
+// size: is the length of the document, see `DocumentLength`
 // f: is the frequency of term that was used in the search in this document (see. TF of tf-idf)
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct DocumentMeta {
     pub id: u64,
-    // id of the document in the
     pub size: u64,
     pub f: u64,
 }
@@ -38,64 +36,210 @@ impl DocumentMeta {
 
 impl SymmetricKey {
     pub fn new() -> Self {
-        Self {
-            key: Aes256Gcm::generate_key(OsRng),
-        }
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+
+    pub fn from_bytes(key: &[u8; 32]) -> Self {
+        Self { key: *key }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
     }
 }
 
+impl Default for SymmetricKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Key epoch is a sequence number of the key set that was used to
+// produce a ciphertext, it is incremented on every key rotation
+pub type KeyEpoch = u32;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct EncryptedDocument {
     pub id: u64,
+    // documents stored before key rotation was introduced belong to epoch 0
+    #[serde(default)]
+    pub epoch: KeyEpoch,
+    #[serde(default)]
+    pub algorithm: CipherAlgorithm,
+    #[serde(default)]
+    pub compression: Compression,
     pub nonce: Vec<u8>,
     pub ciphertext: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+// Entries are spread over shards with their own locks, so the index
+// is searched and updated concurrently through a shared reference and
+// a bulk update only blocks lookups that hit the shard being written
+#[derive(Debug)]
 pub struct EncryptedIndex {
-    index: HashMap<Vec<u8>, Vec<u8>>,
+    epoch: KeyEpoch,
+    index: DashMap<Vec<u8>, Vec<u8>>,
+    // sizes of keys and values, kept up to date on every insert
+    bytes: AtomicU64,
+    // milliseconds since the UNIX epoch, 0 if never updated
+    updated_at: AtomicU64,
 }
 
 impl EncryptedIndex {
     pub fn new() -> Self {
+        Self::with_epoch(0)
+    }
+
+    pub fn with_epoch(epoch: KeyEpoch) -> Self {
         Self {
-            index: HashMap::new(),
+            epoch,
+            index: DashMap::new(),
+            bytes: AtomicU64::new(0),
+            updated_at: AtomicU64::new(0),
         }
     }
 
+    pub fn epoch(&self) -> KeyEpoch {
+        self.epoch
+    }
+
     pub fn len(&self) -> usize {
         self.index.len()
     }
 
-    pub fn update(&mut self, index_update: &EncryptedIndexUpdate) {
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // Memory taken by keys and values
+    pub fn size_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn last_update(&self) -> Option<SystemTime> {
+        match self.updated_at.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+
+    pub fn update(&self, index_update: &EncryptedIndexUpdate) {
         index_update.add.iter().for_each(|r| {
-            self.index.insert(r.0.clone(), r.1.clone());
+            self.insert(r.0.clone(), r.1.clone());
         });
+        self.touch();
+    }
+
+    pub fn add(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.insert(key, value);
+        self.touch();
     }
 
-    pub fn add(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        self.index.insert(key, value);
+    pub fn extend(&self, records: Vec<EncryptedTerm2Document>) {
+        if records.is_empty() {
+            return;
+        }
+        for r in records {
+            self.insert(r.0, r.1);
+        }
+        self.touch();
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
-        self.index.get(key)
+    // A record with an empty value is a tombstone, it removes the entry
+    // of its key. Searches get an empty value for missing keys anyway
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) {
+        let key_size = key.len() as u64;
+        let old = if value.is_empty() {
+            self.index.remove(&key).map(|(_, old)| old)
+        } else {
+            self.bytes
+                .fetch_add(key_size + value.len() as u64, Ordering::Relaxed);
+            self.index.insert(key, value)
+        };
+        if let Some(old) = old {
+            self.bytes
+                .fetch_sub(key_size + old.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn touch(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.updated_at
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Values are copied out, so no shard stays locked after the lookup
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.index.get(key).map(|value| value.clone())
+    }
+}
+
+impl Default for EncryptedIndex {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct EncryptedDocumentStorage {
     pub documents: HashMap<u64, EncryptedDocument>,
+    // time of the last `add` or `retain_epoch`
+    #[serde(skip)]
+    updated_at: Option<SystemTime>,
 }
 
 impl EncryptedDocumentStorage {
     pub fn new() -> Self {
         Self {
             documents: HashMap::new(),
+            updated_at: None,
         }
     }
 
+    // Bytes of nonces and ciphertexts of all documents
+    pub fn size_bytes(&self) -> u64 {
+        self.documents
+            .values()
+            .map(|d| (d.nonce.len() + d.ciphertext.len()) as u64)
+            .sum()
+    }
+
+    pub fn last_update(&self) -> Option<SystemTime> {
+        self.updated_at
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    // drops all documents that were encrypted with keys of another epoch,
+    // returns the number of removed documents
+    pub fn retain_epoch(&mut self, epoch: KeyEpoch) -> usize {
+        let before = self.documents.len();
+        self.documents.retain(|_, d| d.epoch == epoch);
+        self.updated_at = Some(SystemTime::now());
+        before - self.documents.len()
+    }
+
     pub fn add(&mut self, document: EncryptedDocument) {
         self.documents.insert(document.id, document);
+        self.updated_at = Some(SystemTime::now());
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<EncryptedDocument> {
+        let removed = self.documents.remove(&id);
+        if removed.is_some() {
+            self.updated_at = Some(SystemTime::now());
+        }
+        removed
     }
 
     pub fn get(&self, id: u64) -> Option<&EncryptedDocument> {
@@ -103,6 +247,12 @@ impl EncryptedDocumentStorage {
     }
 }
 
+impl Default for EncryptedDocumentStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(PartialEq, Debug, Serialize, Deserialize)]
 pub struct EncryptedTerm2Document(Vec<u8>, Vec<u8>);
 
@@ -135,30 +285,46 @@ impl EncryptedIndexUpdate {
     pub fn len(&self) -> usize {
         self.add.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty()
+    }
+
+    pub fn records(&self) -> &[EncryptedTerm2Document] {
+        &self.add
+    }
+
+    pub fn into_records(self) -> Vec<EncryptedTerm2Document> {
+        self.add
+    }
 }
 
-fn initialize_sha256(term: &Term, key: &[u8]) -> CoreWrapper<Sha3_256Core> {
-    let mut hasher = Sha3_256::new();
-    Digest::update(&mut hasher, key);
-    Digest::update(&mut hasher, &term.term.as_bytes());
-    hasher
+impl Default for EncryptedIndexUpdate {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-fn initialize_hasher_sha256(term: &Term, key: &[u8]) -> CoreWrapper<Sha3_256Core> {
-    let mut hasher = initialize_sha256(term, key);
-    Digest::update(&mut hasher, &term.id.to_be_bytes());
-    hasher
+type HmacSha3_256 = Hmac<Sha3_256>;
+
+// PRF over the term and its sequence number, every variable length
+// input is prefixed with its length so different (term, id) pairs
+// never produce the same input
+fn prf(term: &Term, key: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <HmacSha3_256 as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&(term.term.len() as u64).to_be_bytes());
+    mac.update(term.term.as_bytes());
+    mac.update(&term.id.to_be_bytes());
+    mac.finalize().into_bytes().into()
 }
 
 pub fn encrypt_index_key(term: &Term, key: &[u8]) -> Vec<u8> {
-    let hasher = initialize_hasher_sha256(&term, key);
-    hasher.finalize().to_vec()
+    prf(term, key).to_vec()
 }
 
 pub fn encrypt_index_value(term: &Term, meta: &DocumentMeta, key: &[u8]) -> Vec<u8> {
-    let mut hasher = initialize_hasher_sha256(term, key);
-
-    let arr = hasher.finalize();
+    let arr = prf(term, key);
     let p1 = u64::from_be_bytes(arr[0..8].try_into().unwrap());
     let p2 = u64::from_be_bytes(arr[8..16].try_into().unwrap());
     let p3 = u64::from_be_bytes(arr[16..24].try_into().unwrap());
@@ -170,93 +336,113 @@ pub fn encrypt_index_value(term: &Term, meta: &DocumentMeta, key: &[u8]) -> Vec<
     v
 }
 
-pub fn get_document_meta(term: &Term, value: &Vec<u8>, key: &[u8]) -> DocumentMeta {
-    let hasher = initialize_hasher_sha256(&term, key);
+// Size of an encrypted index value: document id, term frequency and size
+pub const INDEX_VALUE_SIZE: usize = 24;
+
+// Values come from the server, one of another length is rejected
+pub fn get_document_meta(term: &Term, value: &[u8], key: &[u8]) -> Result<DocumentMeta, Error> {
+    if value.len() != INDEX_VALUE_SIZE {
+        return Err(Error::MalformedMessage(format!(
+            "index value of {} bytes, expected {}",
+            value.len(),
+            INDEX_VALUE_SIZE
+        )));
+    }
 
-    let h = hasher.finalize();
+    let h = prf(term, key);
     let id_xor = u64::from_be_bytes(h[0..8].try_into().unwrap());
     let fr_xor = u64::from_be_bytes(h[8..16].try_into().unwrap());
     let si_xor = u64::from_be_bytes(h[16..24].try_into().unwrap());
 
-    if value.len() != 24 {
-        // rest might be used for checksum
-        panic!("value length is not 24");
-    }
+    let p1 = u64::from_be_bytes(value[0..8].try_into().unwrap());
+    let p2 = u64::from_be_bytes(value[8..16].try_into().unwrap());
+    let p3 = u64::from_be_bytes(value[16..24].try_into().unwrap());
 
-    let mut p1: [u8; 8] = [0; 8];
-    let mut p2: [u8; 8] = [0; 8];
-    let mut p3: [u8; 8] = [0; 8];
-
-    p1.copy_from_slice(&value[0..8]);
-    p2.copy_from_slice(&value[8..16]);
-    p3.copy_from_slice(&value[16..24]);
-
-    let p1 = u64::from_be_bytes(p1);
-    let p2 = u64::from_be_bytes(p2);
-    let p3 = u64::from_be_bytes(p3);
-
-    DocumentMeta {
+    Ok(DocumentMeta {
         id: id_xor ^ p1,
         f: fr_xor ^ p2,
         size: si_xor ^ p3,
-    }
+    })
 }
 
-pub fn encrypt_index_update(
-    index_update: &IndexUpdate,
-    k1: &[u8],
-    k2: &[u8],
-) -> EncryptedIndexUpdate {
-    let mut encr = EncryptedIndexUpdate::new();
-
-    index_update.relations.iter().for_each(|r| {
-        let key_vec = encrypt_index_key(&r.term, k1);
-        let meta = DocumentMeta::new(
-            r.document.id,
-            r.document.content.len() as u64,
-            r.freq as u64,
-        );
-        let value_vec = encrypt_index_value(&r.term, &meta, k2);
-        encr.add(key_vec, value_vec);
-    });
-
-    encr
+// Document id and key epoch are authenticated as associated data, so the
+// server cannot return a ciphertext of one document for a request of another
+// or relabel a ciphertext with another epoch
+fn associated_data(id: u64, epoch: KeyEpoch) -> [u8; 12] {
+    let mut aad = [0u8; 12];
+    aad[..8].copy_from_slice(&id.to_be_bytes());
+    aad[8..].copy_from_slice(&epoch.to_be_bytes());
+    aad
 }
 
-pub fn encrypt(document: &Document, key: &SymmetricKey) -> EncryptedDocument {
+pub fn encrypt(
+    document: &Document,
+    key: &SymmetricKey,
+    epoch: KeyEpoch,
+    algorithm: CipherAlgorithm,
+    compression: Compression,
+) -> EncryptedDocument {
     // serialize document using serde to a byte array
-    // encrypt the byte array
+    // compress and encrypt the byte array
     // return the nonce and ciphertext
 
-    let cipher = Aes256Gcm::new(&key.key);
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-    let bytes = serde_json::to_vec(&document).unwrap();
-    let ciphertext = cipher.encrypt(&nonce, bytes.as_ref()).unwrap();
+    let bytes = compression.compress(&serde_json::to_vec(&document).unwrap());
+    let sealed = algorithm.encrypt(key.as_bytes(), &bytes, &associated_data(document.id, epoch));
 
     EncryptedDocument {
         id: document.id,
-        nonce: nonce.to_vec(),
-        ciphertext,
+        epoch,
+        algorithm,
+        compression,
+        nonce: sealed.nonce,
+        ciphertext: sealed.ciphertext,
     }
 }
 
-pub fn decrypt(document: &EncryptedDocument, key: &SymmetricKey) -> Document {
+// Decrypts a document that was requested by `id`, the id is checked
+// against the stored one, the associated data and the plaintext
+pub fn decrypt(
+    id: u64,
+    document: &EncryptedDocument,
+    key: &SymmetricKey,
+) -> Result<Document, Error> {
     // decrypt the ciphertext using the nonce and key
     // deserialize the byte array using serde
     // return the document
 
-    let cipher = Aes256Gcm::new(&key.key);
-    let nonce = Nonce::<AesGcm<Aes256, U12>>::from_slice(&document.nonce);
-    let plaintext = cipher.decrypt(nonce, document.ciphertext.as_ref()).unwrap();
-    let document: Document = serde_json::from_slice(&plaintext).unwrap();
+    if document.id != id {
+        return Err(Error::DocumentIdMismatch {
+            requested: id,
+            found: document.id,
+        });
+    }
 
-    document
+    let plaintext = document.algorithm.decrypt(
+        key.as_bytes(),
+        &document.nonce,
+        &document.ciphertext,
+        &associated_data(id, document.epoch),
+    )?;
+    let plaintext = document.compression.decompress(&plaintext)?;
+    let decrypted: Document =
+        serde_json::from_slice(&plaintext).map_err(|e| Error::MalformedDocument(e.to_string()))?;
+
+    if decrypted.id != id {
+        return Err(Error::DocumentIdMismatch {
+            requested: id,
+            found: decrypted.id,
+        });
+    }
+    Ok(decrypted)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use hex_literal::hex;
+    use proptest::prelude::*;
+
     #[test]
     fn test_hashing_and_xor() {
         let t = &Term {
@@ -269,9 +455,37 @@ mod tests {
         let meta = DocumentMeta::new(78361473624, 523232, 42484759348);
 
         let hash = encrypt_index_value(t, &meta, &key);
-        let meta2 = get_document_meta(t, &hash, &key);
+        let meta2 = get_document_meta(t, &hash, &key).unwrap();
 
         assert_eq!(meta, meta2);
+        assert!(matches!(
+            get_document_meta(t, &hash[1..], &key),
+            Err(Error::MalformedMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_index_key_vectors() {
+        let key = [42u8; 32];
+
+        let t = Term::new("fox".to_string(), 1);
+        assert_eq!(
+            encrypt_index_key(&t, &key),
+            hex!("808874be2156f8a1e3877a1296cf690b8a01e2c09e89520052789deb6031a363")
+        );
+
+        let t = Term::new("ab".to_string(), 1);
+        assert_eq!(
+            encrypt_index_key(&t, &key),
+            hex!("6cdd33195b904e1f53a7be79d87611aea84fd5e02ed4e00ddddf0bc2a8e3f019")
+        );
+
+        let meta = DocumentMeta::new(1, 2, 3);
+        let t = Term::new("fox".to_string(), 1);
+        assert_eq!(
+            encrypt_index_value(&t, &meta, &key),
+            hex!("808874be2156f8a0e3877a1296cf69088a01e2c09e895202")
+        );
     }
 
     #[test]
@@ -283,9 +497,276 @@ mod tests {
             title: "title".to_string(),
             content: "body".to_string(),
         };
-        let encrypted_document = encrypt(&document, &key);
-        let decrypted_document = decrypt(&encrypted_document, &key);
+        let encrypted_document = encrypt(
+            &document,
+            &key,
+            7,
+            CipherAlgorithm::default(),
+            Compression::default(),
+        );
+        assert_eq!(encrypted_document.epoch, 7);
+        let decrypted_document = decrypt(42, &encrypted_document, &key).unwrap();
 
         assert_eq!(document, decrypted_document);
     }
+
+    #[test]
+    fn test_encrypt_decrypt_compressed() {
+        let key = SymmetricKey::new();
+        let document = Document {
+            id: 42,
+            title: "title".to_string(),
+            content: "the quick brown fox jumps over the lazy dog ".repeat(200),
+        };
+
+        let plain = encrypt(
+            &document,
+            &key,
+            0,
+            CipherAlgorithm::default(),
+            Compression::None,
+        );
+        let compressed = encrypt(
+            &document,
+            &key,
+            0,
+            CipherAlgorithm::default(),
+            Compression::Zstd,
+        );
+
+        assert_eq!(compressed.compression, Compression::Zstd);
+        assert!(compressed.ciphertext.len() < plain.ciphertext.len());
+        assert_eq!(decrypt(42, &compressed, &key).unwrap(), document);
+    }
+
+    #[test]
+    fn test_swapped_ciphertext() {
+        let key = SymmetricKey::new();
+        let document = Document {
+            id: 1,
+            title: "".to_string(),
+            content: "first".to_string(),
+        };
+        let other = Document {
+            id: 2,
+            title: "".to_string(),
+            content: "second".to_string(),
+        };
+
+        for algorithm in [
+            CipherAlgorithm::Aes256Gcm,
+            CipherAlgorithm::Aes256GcmSiv,
+            CipherAlgorithm::XChaCha20Poly1305,
+        ] {
+            let mut swapped = encrypt(&document, &key, 0, algorithm, Compression::None);
+            assert_eq!(decrypt(1, &swapped, &key).unwrap(), document);
+
+            // server returns the second document as is for id=1
+            let enc_other = encrypt(&other, &key, 0, algorithm, Compression::None);
+            assert_eq!(
+                decrypt(1, &enc_other, &key),
+                Err(Error::DocumentIdMismatch {
+                    requested: 1,
+                    found: 2
+                })
+            );
+
+            // server relabels ciphertext of the second document with id=1
+            swapped.nonce = enc_other.nonce;
+            swapped.ciphertext = enc_other.ciphertext;
+            assert_eq!(decrypt(1, &swapped, &key), Err(Error::Decryption));
+        }
+    }
+
+    #[test]
+    fn test_relabeled_epoch() {
+        let key = SymmetricKey::new();
+        let document = Document {
+            id: 1,
+            title: "".to_string(),
+            content: "body".to_string(),
+        };
+
+        let mut encrypted = encrypt(
+            &document,
+            &key,
+            3,
+            CipherAlgorithm::default(),
+            Compression::default(),
+        );
+        encrypted.epoch = 4;
+        assert_eq!(decrypt(1, &encrypted, &key), Err(Error::Decryption));
+    }
+
+    #[test]
+    fn test_inner_id_mismatch() {
+        // a document whose plaintext id differs from the id it is stored under,
+        // e.g. produced by a buggy client, is rejected after decryption
+        let key = SymmetricKey::new();
+        let document = Document {
+            id: 2,
+            title: "".to_string(),
+            content: "body".to_string(),
+        };
+        let bytes = serde_json::to_vec(&document).unwrap();
+        let sealed =
+            CipherAlgorithm::default().encrypt(key.as_bytes(), &bytes, &associated_data(1, 0));
+        let encrypted = EncryptedDocument {
+            id: 1,
+            epoch: 0,
+            algorithm: CipherAlgorithm::default(),
+            compression: Compression::None,
+            nonce: sealed.nonce,
+            ciphertext: sealed.ciphertext,
+        };
+
+        assert_eq!(
+            decrypt(1, &encrypted, &key),
+            Err(Error::DocumentIdMismatch {
+                requested: 1,
+                found: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_retain_epoch() {
+        let old_key = SymmetricKey::new();
+        let new_key = SymmetricKey::new();
+        let document = Document {
+            id: 1,
+            title: "".to_string(),
+            content: "body".to_string(),
+        };
+
+        let mut storage = EncryptedDocumentStorage::new();
+        let (algorithm, compression) = (CipherAlgorithm::default(), Compression::default());
+        storage.add(encrypt(&document, &old_key, 1, algorithm, compression));
+        storage.add(encrypt(
+            &Document { id: 2, ..document },
+            &new_key,
+            2,
+            algorithm,
+            compression,
+        ));
+
+        assert_eq!(storage.retain_epoch(2), 1);
+        assert!(storage.get(1).is_none());
+        assert_eq!(storage.get(2).unwrap().epoch, 2);
+    }
+
+    #[test]
+    fn test_index_shared_between_threads() {
+        let index = EncryptedIndex::new();
+        index.add(b"existing".to_vec(), b"value".to_vec());
+
+        std::thread::scope(|scope| {
+            for writer in 0..4u8 {
+                let index = &index;
+                scope.spawn(move || {
+                    index.extend(
+                        (0..1000u16)
+                            .map(|i| {
+                                let mut key = vec![writer];
+                                key.extend_from_slice(&i.to_be_bytes());
+                                EncryptedTerm2Document::new(key, vec![writer])
+                            })
+                            .collect(),
+                    );
+                });
+            }
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        assert_eq!(index.get(b"existing"), Some(b"value".to_vec()));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(index.len(), 4001);
+        assert_eq!(index.get(&[3, 0, 7]), Some(vec![3]));
+        assert_eq!(index.size_bytes(), 13 + 4000 * 4);
+    }
+
+    #[test]
+    fn test_index_size_and_update_time() {
+        let index = EncryptedIndex::new();
+        assert_eq!(index.size_bytes(), 0);
+        assert!(index.last_update().is_none());
+
+        index.add(vec![1; 32], vec![2; 24]);
+        index.add(vec![1; 32], vec![3; 8]);
+        assert_eq!(index.size_bytes(), 40);
+        assert!(index.last_update().unwrap() <= SystemTime::now());
+
+        // tombstones remove the entry, also when it does not exist
+        index.add(vec![1; 32], Vec::new());
+        index.add(vec![4; 32], Vec::new());
+        assert!(index.is_empty());
+        assert_eq!(index.size_bytes(), 0);
+    }
+
+    fn algorithm() -> impl Strategy<Value = CipherAlgorithm> {
+        prop_oneof![
+            Just(CipherAlgorithm::Aes256Gcm),
+            Just(CipherAlgorithm::Aes256GcmSiv),
+            Just(CipherAlgorithm::XChaCha20Poly1305),
+        ]
+    }
+
+    fn compression() -> impl Strategy<Value = Compression> {
+        prop_oneof![Just(Compression::None), Just(Compression::Zstd)]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_index_value_roundtrip(
+            term in "\\PC*",
+            seq: u64,
+            id: u64,
+            size: u64,
+            f: u64,
+            key in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let term = Term::new(term, seq);
+            let value = encrypt_index_value(&term, &DocumentMeta::new(id, size, f), &key);
+            prop_assert_eq!(value.len(), INDEX_VALUE_SIZE);
+            prop_assert_eq!(get_document_meta(&term, &value, &key), Ok(DocumentMeta::new(id, size, f)));
+        }
+
+        // the length prefix keeps ("ab", 1) and ("a", ...) apart
+        #[test]
+        fn prop_index_keys_are_distinct(a in "\\PC{0,8}", b in "\\PC{0,8}", i: u64, j: u64) {
+            prop_assume!(a != b || i != j);
+            let key = [7u8; 32];
+            prop_assert_ne!(
+                encrypt_index_key(&Term::new(a, i), &key),
+                encrypt_index_key(&Term::new(b, j), &key)
+            );
+        }
+
+        #[test]
+        fn prop_encrypt_decrypt_roundtrip(
+            id: u64,
+            title in "\\PC*",
+            content in "\\PC*",
+            epoch: KeyEpoch,
+            algorithm in algorithm(),
+            compression in compression(),
+            flipped: usize,
+        ) {
+            let key = SymmetricKey::from_bytes(&[42u8; 32]);
+            let document = Document { id, title, content };
+            let encrypted = encrypt(&document, &key, epoch, algorithm, compression);
+            prop_assert_eq!(decrypt(id, &encrypted, &key), Ok(document));
+            prop_assert!(decrypt(id.wrapping_add(1), &encrypted, &key).is_err());
+
+            // any modified byte of the ciphertext is rejected
+            let mut modified = encrypted.clone();
+            let i = flipped % modified.ciphertext.len();
+            modified.ciphertext[i] ^= 1;
+            prop_assert!(decrypt(id, &modified, &key).is_err());
+        }
+    }
 }
@@ -0,0 +1,54 @@
+use crate::emb25::crypto::KeyEpoch;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // passphrase does not match the verification tag
+    // stored along with the key derivation parameters
+    WrongPassphrase,
+    // key derivation parameters are out of the supported range
+    InvalidKdfParams(String),
+    // ciphertext was not produced with this key, algorithm and associated
+    // data, or it was modified in the storage
+    Decryption,
+    // plaintext was authenticated but cannot be deserialized
+    MalformedDocument(String),
+    // server returned another document than the one that was requested
+    DocumentIdMismatch { requested: u64, found: u64 },
+    // request or response body cannot be decoded in its wire format
+    MalformedMessage(String),
+    // document is encrypted under a key epoch the client has no keys for
+    UnknownEpoch(KeyEpoch),
+    // setting can only be changed before documents are indexed
+    IndexNotEmpty,
+    // imported document has the id of an indexed one
+    DuplicateDocument(u64),
+    // line of an imported JSONL file is not a document
+    MalformedRecord { line: usize, reason: String },
+    // imported or exported file cannot be read or written
+    Io(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WrongPassphrase => write!(f, "wrong passphrase"),
+            Error::InvalidKdfParams(e) => write!(f, "invalid key derivation parameters: {}", e),
+            Error::Decryption => write!(f, "document cannot be decrypted"),
+            Error::MalformedDocument(e) => write!(f, "malformed document: {}", e),
+            Error::DocumentIdMismatch { requested, found } => {
+                write!(f, "document id={} was returned for id={}", found, requested)
+            }
+            Error::MalformedMessage(e) => write!(f, "malformed message: {}", e),
+            Error::UnknownEpoch(epoch) => write!(f, "no keys for key epoch {}", epoch),
+            Error::IndexNotEmpty => write!(f, "documents are already indexed"),
+            Error::DuplicateDocument(id) => write!(f, "document id={} already exists", id),
+            Error::MalformedRecord { line, reason } => {
+                write!(f, "malformed record on line {}: {}", line, reason)
+            }
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
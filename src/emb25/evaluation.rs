@@ -0,0 +1,312 @@
+use crate::emb25::error::Error;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+// Relevance judgments of a TREC qrels file, one judgment per line:
+// `query-id iteration document-id relevance`. The iteration is ignored,
+// documents with a relevance of 0 or less are not relevant
+#[derive(Debug, Default, PartialEq)]
+pub struct Qrels {
+    queries: HashMap<String, HashMap<String, i32>>,
+}
+
+impl Qrels {
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut qrels = Qrels::default();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| Error::Io(e.to_string()))?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let malformed = |reason: String| Error::MalformedRecord {
+                line: i + 1,
+                reason,
+            };
+            let [query, _, document, relevance] = fields[..] else {
+                return Err(malformed(format!(
+                    "expected 4 fields, found {}",
+                    fields.len()
+                )));
+            };
+            let relevance = relevance
+                .parse()
+                .map_err(|_| malformed(format!("relevance {:?} is not an integer", relevance)))?;
+            qrels
+                .queries
+                .entry(query.to_string())
+                .or_default()
+                .insert(document.to_string(), relevance);
+        }
+        Ok(qrels)
+    }
+
+    // Judged documents of a query with their relevance
+    pub fn judgments(&self, query: &str) -> Option<&HashMap<String, i32>> {
+        self.queries.get(query)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queries.is_empty()
+    }
+}
+
+// Reads queries as `query-id<TAB>text` lines, the format of
+// MS MARCO and BEIR query sets. Blank lines are skipped
+pub fn read_queries<R: BufRead>(reader: R) -> Result<Vec<(String, String)>, Error> {
+    let mut queries = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| Error::Io(e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((id, text)) = line.split_once('\t') else {
+            return Err(Error::MalformedRecord {
+                line: i + 1,
+                reason: "expected a query id and a tab before the text".to_string(),
+            });
+        };
+        queries.push((id.trim().to_string(), text.trim().to_string()));
+    }
+    Ok(queries)
+}
+
+// Metrics of one ranked list, best first. nDCG and recall are cut at k,
+// average precision and the reciprocal rank use the whole list, so
+// they depend on how many documents were retrieved
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Metrics {
+    pub ndcg: f64,
+    pub average_precision: f64,
+    pub reciprocal_rank: f64,
+    pub recall: f64,
+}
+
+impl Metrics {
+    pub fn new(ranked: &[String], judgments: &HashMap<String, i32>, k: usize) -> Self {
+        let relevance = |document: &String| judgments.get(document).copied().unwrap_or(0).max(0);
+        let relevant = judgments.values().filter(|r| **r > 0).count();
+        if relevant == 0 {
+            return Metrics::default();
+        }
+
+        // gains are the relevance grades, as in trec_eval
+        let dcg = |grades: &mut dyn Iterator<Item = i32>| -> f64 {
+            grades
+                .take(k)
+                .enumerate()
+                .map(|(i, grade)| grade as f64 / (i as f64 + 2.).log2())
+                .sum()
+        };
+        let mut ideal: Vec<i32> = judgments.values().map(|r| (*r).max(0)).collect();
+        ideal.sort_unstable_by(|a, b| b.cmp(a));
+        let ndcg = dcg(&mut ranked.iter().map(relevance)) / dcg(&mut ideal.into_iter());
+
+        let mut found = 0;
+        let mut precisions = 0.;
+        let mut reciprocal_rank = 0.;
+        for (i, document) in ranked.iter().enumerate() {
+            if relevance(document) > 0 {
+                found += 1;
+                precisions += found as f64 / (i + 1) as f64;
+                if found == 1 {
+                    reciprocal_rank = 1. / (i + 1) as f64;
+                }
+            }
+        }
+        let found_at_k = ranked.iter().take(k).filter(|d| relevance(d) > 0).count();
+
+        Metrics {
+            ndcg,
+            average_precision: precisions / relevant as f64,
+            reciprocal_rank,
+            recall: found_at_k as f64 / relevant as f64,
+        }
+    }
+}
+
+// Metrics of a query set, averaged over the queries that have
+// relevance judgments. Queries are searched by the caller, so the
+// same evaluation runs in process or against a server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Evaluation {
+    k: usize,
+    queries: Vec<(String, Metrics)>,
+    // queries without relevant documents in the qrels
+    unjudged: usize,
+}
+
+impl Evaluation {
+    pub fn new(k: usize) -> Self {
+        Self {
+            k,
+            queries: Vec::new(),
+            unjudged: 0,
+        }
+    }
+
+    // Adds the documents found for a query, best first. Returns
+    // false if the query has no relevant documents and is skipped
+    pub fn add(&mut self, query: &str, ranked: &[String], qrels: &Qrels) -> bool {
+        match qrels.judgments(query) {
+            Some(judgments) if judgments.values().any(|r| *r > 0) => {
+                self.queries
+                    .push((query.to_string(), Metrics::new(ranked, judgments, self.k)));
+                true
+            }
+            _ => {
+                self.unjudged += 1;
+                false
+            }
+        }
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn queries(&self) -> &[(String, Metrics)] {
+        &self.queries
+    }
+
+    pub fn unjudged(&self) -> usize {
+        self.unjudged
+    }
+
+    pub fn ndcg(&self) -> f64 {
+        self.mean(|m| m.ndcg)
+    }
+
+    pub fn map(&self) -> f64 {
+        self.mean(|m| m.average_precision)
+    }
+
+    pub fn mrr(&self) -> f64 {
+        self.mean(|m| m.reciprocal_rank)
+    }
+
+    pub fn recall(&self) -> f64 {
+        self.mean(|m| m.recall)
+    }
+
+    fn mean(&self, metric: impl Fn(&Metrics) -> f64) -> f64 {
+        if self.queries.is_empty() {
+            return 0.;
+        }
+        self.queries.iter().map(|(_, m)| metric(m)).sum::<f64>() / self.queries.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emb25::corpus::CorpusDocument;
+    use crate::emb25::crypto::EncryptedIndex;
+    use crate::emb25::indexer::Indexer;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_read_qrels_and_queries() {
+        let qrels = Qrels::read("1 0 d1 2\n1 0 d2 0\n\n2 Q0 d3 -1\n".as_bytes()).unwrap();
+        assert_eq!(qrels.len(), 2);
+        assert_eq!(qrels.judgments("1").unwrap()["d1"], 2);
+        assert_eq!(qrels.judgments("2").unwrap()["d3"], -1);
+        assert!(matches!(
+            Qrels::read("1 0 d1\n".as_bytes()),
+            Err(Error::MalformedRecord { line: 1, .. })
+        ));
+        assert!(matches!(
+            Qrels::read("1 0 d1 1\n1 0 d2 high\n".as_bytes()),
+            Err(Error::MalformedRecord { line: 2, .. })
+        ));
+
+        let queries = read_queries("1\tbrown fox\n\n2\tlazy dog\n".as_bytes()).unwrap();
+        assert_eq!(
+            queries,
+            vec![
+                ("1".to_string(), "brown fox".to_string()),
+                ("2".to_string(), "lazy dog".to_string()),
+            ]
+        );
+        assert!(read_queries("no tab\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let judgments = HashMap::from([
+            ("a".to_string(), 2),
+            ("b".to_string(), 1),
+            ("c".to_string(), 0),
+            ("d".to_string(), 1),
+        ]);
+        let metrics = Metrics::new(&ids(&["c", "a", "x", "b"]), &judgments, 3);
+
+        // relevant documents at ranks 2 and 4, "d" is not found
+        assert!((metrics.average_precision - (1. / 2. + 2. / 4.) / 3.).abs() < 1e-12);
+        assert_eq!(metrics.reciprocal_rank, 0.5);
+        assert!((metrics.recall - 1. / 3.).abs() < 1e-12);
+        let dcg = 2. / 3f64.log2();
+        let ideal = 2. + 1. / 3f64.log2() + 1. / 2.;
+        assert!((metrics.ndcg - dcg / ideal).abs() < 1e-12);
+
+        let perfect = Metrics::new(&ids(&["a", "b", "d"]), &judgments, 3);
+        assert!((perfect.ndcg - 1.).abs() < 1e-12);
+        assert_eq!(perfect.average_precision, 1.);
+        assert_eq!(Metrics::new(&[], &judgments, 3), Metrics::default());
+    }
+
+    #[test]
+    fn test_evaluate_encrypted_search() {
+        let corpus = [
+            (1, "the quick brown fox jumps over the lazy dog"),
+            (2, "a lazy dog sleeps in the sun"),
+            (3, "foxes and a fox hunt at night"),
+            (4, "brown bears live in the forest"),
+        ];
+        let mut indexer = Indexer::new();
+        for (id, text) in corpus {
+            indexer
+                .import(CorpusDocument {
+                    id: Some(id),
+                    title: String::new(),
+                    body: text.to_string(),
+                })
+                .unwrap();
+        }
+        let index = EncryptedIndex::new();
+        index.update(&indexer.get_encrypted_index());
+
+        let qrels = Qrels::read("q1 0 3 1\nq1 0 1 1\nq2 0 4 1\nq3 0 2 0\n".as_bytes()).unwrap();
+        let queries = read_queries("q1\tfox\nq2\tforest bears\nq3\tsun\n".as_bytes()).unwrap();
+        let mut evaluation = Evaluation::new(10);
+        for (id, text) in queries.iter() {
+            let query = indexer.query(text.clone());
+            let values: Vec<Vec<u8>> = query
+                .query
+                .iter()
+                .map(|key| index.get(key).unwrap_or_default())
+                .collect();
+            let ranked: Vec<String> = indexer
+                .rank(&query, &values)
+                .into_iter()
+                .map(|(id, _)| id.to_string())
+                .collect();
+            evaluation.add(id, &ranked, &qrels);
+        }
+
+        // both queries find all of their relevant documents first
+        assert_eq!(evaluation.queries().len(), 2);
+        assert_eq!(evaluation.unjudged(), 1);
+        assert_eq!(evaluation.map(), 1.);
+        assert_eq!(evaluation.mrr(), 1.);
+        assert_eq!(evaluation.recall(), 1.);
+        assert!((evaluation.ndcg() - 1.).abs() < 1e-12);
+    }
+}
@@ -1,6 +1,4 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Mutex;
 
 #[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
 pub struct Document {
@@ -35,18 +33,3 @@ impl Term {
         self.score *= score;
     }
 }
-
-#[derive(PartialEq, Debug)]
-pub struct Term2Document {
-    pub term: Term,
-
-    // How many times this term is seen in the document
-    pub freq: u64,
-
-    pub document: Document,
-}
-
-#[derive(PartialEq, Debug)]
-pub struct IndexUpdate {
-    pub relations: Vec<Term2Document>,
-}
@@ -1,52 +1,110 @@
+use crate::emb25::cipher::CipherAlgorithm;
+use crate::emb25::compression::Compression;
+use crate::emb25::corpus::CorpusDocument;
 use crate::emb25::crypto::{
     decrypt, encrypt, encrypt_index_key, encrypt_index_value, get_document_meta, DocumentMeta,
-    EncryptedDocument, EncryptedDocumentStorage, EncryptedIndexUpdate, EncryptedTerm2Document,
-    SymmetricKey,
+    EncryptedDocument, EncryptedIndexUpdate, EncryptedTerm2Document, KeyEpoch,
 };
-use crate::emb25::index::{Term, Term2Document};
-use crate::{group_by, tokenize, Document, IndexUpdate};
+use crate::emb25::error::Error;
+use crate::emb25::index::Term;
+use crate::emb25::keys::{Keys, MasterKey, PassphraseParams};
+use crate::{group_by, tokenize, Document};
 use rand::{rngs::OsRng, RngCore};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-struct Keys {
-    document_key: SymmetricKey,
-    index_key: Vec<u8>,
-    value_key: Vec<u8>,
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Query {
+    pub terms: Vec<Term>,
+    pub query: Vec<Vec<u8>>,
 }
 
-impl Keys {
-    fn generate_secure_random(size: usize) -> Vec<u8> {
-        let mut rng = OsRng::default();
-        let mut buffer = vec![0u8; size];
-        rng.fill_bytes(&mut buffer);
-        buffer
-    }
+// Collection of indexers that were not given one explicitly
+pub const DEFAULT_COLLECTION: &str = "default";
+
+// Unit of document lengths for BM25 length normalization. Standard BM25
+// counts tokens, byte lengths penalize documents with non-ASCII text
+// and are kept only for indexes that were built with them
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DocumentLength {
+    #[default]
+    Tokens,
+    Bytes,
+}
 
-    pub fn new() -> Self {
-        Self {
-            document_key: SymmetricKey::new(),
-            index_key: Self::generate_secure_random(32),
-            value_key: Self::generate_secure_random(32),
+impl DocumentLength {
+    fn of(&self, text: &str, tokens: usize) -> u64 {
+        match self {
+            DocumentLength::Tokens => tokens as u64,
+            DocumentLength::Bytes => text.len() as u64,
         }
     }
 }
 
+// Everything the client has to persist between runs besides the master
+// key, see `Indexer::snapshot` and `Indexer::restore`. It holds no
+// document text, documents are stored only on the server
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct Query {
-    pub terms: Vec<Term>,
-    pub query: Vec<Vec<u8>>,
+pub struct IndexerState {
+    pub collection: String,
+    pub epoch: KeyEpoch,
+    pub cipher: CipherAlgorithm,
+    pub compression: Compression,
+    pub document_length: DocumentLength,
+    pub dictionary: Dictionary,
+    // lengths of the indexed documents by their ids
+    pub lengths: HashMap<u64, u64>,
+    // postings of every term, see `Postings`
+    pub postings: Postings,
+    // postings of every term the server has, see `Uploaded`
+    pub uploaded: BTreeMap<String, Uploaded>,
+    pub total_document_size: u64,
+}
+
+// Postings by their term, the term is stored once. A posting is indexed
+// under its position in the list of the term, starting with 1
+pub type Postings = BTreeMap<String, Vec<Posting>>;
+
+// Index record, the only things the index needs to
+// know about a document are its id and length
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct Posting {
+    pub document: u64,
+    pub length: u64,
+    pub freq: u64,
+}
+
+// Postings of a term as of the last upload, so a flush only sends the
+// entries that changed since then, see `Indexer::index_changes`
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug, Default)]
+pub struct Uploaded {
+    // number of postings the server has
+    pub len: u64,
+    // positions below `len` that hold another posting now
+    pub changed: BTreeSet<u64>,
 }
 
 pub struct Indexer {
-    // client only needs to persist dictionary
-    // and total document size, the rest can be
-    // reconstructed from the server state
+    // dictionary and postings are persisted by the client with
+    // `snapshot`, the documents themselves only by the server
     pub dictionary: Dictionary,
 
+    // server collection the index and documents are stored in,
+    // all keys are derived for it, see `Keys::derive`
+    collection: String,
+    master_key: MasterKey,
     keys: Keys,
-    documents: HashMap<u64, Document>,
-    index_records: Vec<Term2Document>,
+    cipher: CipherAlgorithm,
+    compression: Compression,
+    document_length: DocumentLength,
+    // documents are encrypted as soon as they are indexed and their
+    // text is dropped, only their lengths are kept
+    lengths: HashMap<u64, u64>,
+    postings: Postings,
+    uploaded: BTreeMap<String, Uploaded>,
 
     total_document_size: u64,
 }
@@ -83,29 +141,209 @@ impl BM25 {
     }
 }
 
+impl Default for Indexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Indexer {
     pub fn new() -> Self {
+        Self::with_master_key(MasterKey::generate())
+    }
+
+    // Unlocks the master key with a passphrase, see `MasterKey::unlock`
+    pub fn with_passphrase(passphrase: &str, params: &PassphraseParams) -> Result<Self, Error> {
+        Ok(Self::with_master_key(MasterKey::unlock(
+            passphrase, params,
+        )?))
+    }
+
+    pub fn with_master_key(master_key: MasterKey) -> Self {
+        Self::with_collection(DEFAULT_COLLECTION, master_key)
+    }
+
+    pub fn with_collection(collection: &str, master_key: MasterKey) -> Self {
         Self {
             dictionary: Dictionary::new(),
-            keys: Keys::new(),
-            documents: HashMap::new(),
-            index_records: Vec::new(),
+            keys: Keys::derive(&master_key, collection, 0),
+            collection: collection.to_string(),
+            master_key,
+            cipher: CipherAlgorithm::default(),
+            compression: Compression::default(),
+            document_length: DocumentLength::default(),
+            lengths: HashMap::new(),
+            postings: Postings::new(),
+            uploaded: BTreeMap::new(),
             total_document_size: 0u64,
         }
     }
 
-    pub fn meta(&self, term: &Term, value: &Vec<u8>) -> DocumentMeta {
-        get_document_meta(&term, value, &self.keys.value_key)
+    pub fn meta(&self, term: &Term, value: &[u8]) -> Result<DocumentMeta, Error> {
+        get_document_meta(term, value, &self.keys.value_key)
+    }
+
+    // Algorithm for documents encrypted from now on, documents that are
+    // already stored are decrypted with the algorithm they were stored with
+    pub fn set_cipher(&mut self, cipher: CipherAlgorithm) {
+        self.cipher = cipher;
+    }
+
+    // Compression of documents encrypted from now on, compressed
+    // payloads are padded so their size does not leak the ratio
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    // Lengths of indexed documents are not recomputed, so the
+    // unit can only be changed before anything is indexed
+    pub fn set_document_length(&mut self, document_length: DocumentLength) -> Result<(), Error> {
+        if !self.is_empty() {
+            return Err(Error::IndexNotEmpty);
+        }
+        self.document_length = document_length;
+        Ok(())
+    }
+
+    // Restores an indexer persisted with `snapshot`,
+    // keys are derived for the persisted key epoch
+    pub fn restore(master_key: MasterKey, state: IndexerState) -> Self {
+        Self {
+            dictionary: state.dictionary,
+            keys: Keys::derive(&master_key, &state.collection, state.epoch),
+            collection: state.collection,
+            master_key,
+            cipher: state.cipher,
+            compression: state.compression,
+            document_length: state.document_length,
+            lengths: state.lengths,
+            postings: state.postings,
+            uploaded: state.uploaded,
+            total_document_size: state.total_document_size,
+        }
+    }
+
+    pub fn snapshot(&self) -> IndexerState {
+        IndexerState {
+            collection: self.collection.clone(),
+            epoch: self.keys.epoch,
+            cipher: self.cipher,
+            compression: self.compression,
+            document_length: self.document_length,
+            dictionary: Dictionary {
+                terms: self.dictionary.terms.clone(),
+            },
+            lengths: self.lengths.clone(),
+            postings: self.postings.clone(),
+            uploaded: self.uploaded.clone(),
+            total_document_size: self.total_document_size,
+        }
+    }
+
+    pub fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    pub fn epoch(&self) -> KeyEpoch {
+        self.keys.epoch
+    }
+
+    // The epoch of a document comes from the server, it is not trusted.
+    // Keys of earlier epochs are derived again from the master key, so
+    // documents stored under any of them can still be decrypted
+    fn keys_for(&self, epoch: KeyEpoch) -> Result<Cow<'_, Keys>, Error> {
+        match epoch.cmp(&self.keys.epoch) {
+            Ordering::Equal => Ok(Cow::Borrowed(&self.keys)),
+            Ordering::Less => Ok(Cow::Owned(Keys::derive(
+                &self.master_key,
+                &self.collection,
+                epoch,
+            ))),
+            Ordering::Greater => Err(Error::UnknownEpoch(epoch)),
+        }
+    }
+
+    // Decrypts a document that was requested from the server by `id`
+    pub fn decrypt(&self, id: u64, enc_doc: &EncryptedDocument) -> Result<Document, Error> {
+        decrypt(id, enc_doc, &self.keys_for(enc_doc.epoch)?.document_key)
+    }
+
+    // Starts key rotation: derives keys for the next epoch, documents that
+    // are still stored under earlier epochs are decrypted with keys derived
+    // for them. After that the client should:
+    //   1. re-encrypt stored documents with `reencrypt`,
+    //   2. re-derive the index with `get_encrypted_index`,
+    //   3. upload both under the new epoch, persist the indexer and then
+    //      switch the server to the new epoch.
+    // Entries of the new epoch are not uploaded yet, `index_changes`
+    // returns the whole index until `mark_uploaded`
+    pub fn rotate_keys(&mut self) -> KeyEpoch {
+        self.keys = Keys::derive(&self.master_key, &self.collection, self.keys.epoch + 1);
+        self.uploaded.clear();
+        self.keys.epoch
+    }
+
+    pub fn reencrypt(
+        &self,
+        id: u64,
+        enc_doc: &EncryptedDocument,
+    ) -> Result<EncryptedDocument, Error> {
+        let document = self.decrypt(id, enc_doc)?;
+        Ok(encrypt(
+            &document,
+            &self.keys.document_key,
+            self.keys.epoch,
+            self.cipher,
+            self.compression,
+        ))
+    }
+
+    pub fn document_ids(&self) -> Vec<u64> {
+        self.lengths.keys().cloned().collect()
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.lengths.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lengths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
     }
 
-    pub fn decrypt(&self, enc_doc: &EncryptedDocument) -> Document {
-        let decr = decrypt(enc_doc, &self.keys.document_key);
-        decr
+    // Removes a document from the index. The last posting of each of its
+    // terms takes its place, so queries still enumerate sequence numbers
+    // 1..=df and a flush rewrites one entry and drops one per term
+    pub fn remove(&mut self, id: u64) -> bool {
+        let Some(length) = self.lengths.remove(&id) else {
+            return false;
+        };
+        self.total_document_size -= length;
+        for (term, postings) in self.postings.iter_mut() {
+            let Some(position) = postings.iter().position(|p| p.document == id) else {
+                continue;
+            };
+            postings.swap_remove(position);
+            if position < postings.len() {
+                mark_changed(&mut self.uploaded, term, position);
+            }
+        }
+        self.postings.retain(|_, postings| !postings.is_empty());
+        self.dictionary.terms = self
+            .postings
+            .iter()
+            .map(|(term, postings)| (term.clone(), postings.len() as u64))
+            .collect();
+
+        true
     }
 
     pub fn bm25(&self) -> BM25 {
-        let avgdl = self.total_document_size as f64 / self.documents.len() as f64;
-        BM25::new(1.2, 0.75, avgdl, self.documents.len() as u64)
+        let avgdl = self.total_document_size as f64 / self.lengths.len() as f64;
+        BM25::new(1.2, 0.75, avgdl, self.lengths.len() as u64)
     }
 
     pub fn query(&self, text: String) -> Query {
@@ -133,71 +371,249 @@ impl Indexer {
         Query { terms, query }
     }
 
-    pub fn add(&mut self, text: String) -> Document {
+    // Scores documents with the index values the server returned for the
+    // keys of the query, in the same order. Values that are not index
+    // values, like the empty ones of keys the server does not have, are
+    // skipped. Documents are ordered by descending score, documents with
+    // equal scores by id
+    pub fn rank(&self, query: &Query, values: &[Vec<u8>]) -> Vec<(u64, f64)> {
+        let bm25 = self.bm25();
+        let mut scores: HashMap<u64, f64> = HashMap::new();
+        for (term, value) in query.terms.iter().zip(values) {
+            let Ok(meta) = self.meta(term, value) else {
+                continue;
+            };
+            let doc_freq = self.dictionary.freq(&term.term).copied().unwrap_or(0);
+            *scores.entry(meta.id).or_insert(0.) += bm25.score(meta.size, meta.f, doc_freq);
+        }
+
+        let mut ranked: Vec<(u64, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+
+    pub fn add(&mut self, text: String) -> EncryptedDocument {
+        self.add_document(String::new(), text)
+    }
+
+    // Indexes the document and returns it encrypted, the indexer keeps
+    // nothing of it but its length. Title is stored encrypted with the
+    // document, only the text is indexed
+    pub fn add_document(&mut self, title: String, text: String) -> EncryptedDocument {
         // generate a random key for the document
-        let mut rng = OsRng::default();
+        let mut rng = OsRng;
         let id = rng.next_u64();
-
-        // store document
-        let document = Document {
+        self.insert(Document {
             id,
-            title: "".to_string(),
-            content: text.clone(),
-        };
+            title,
+            content: text,
+        })
+    }
 
-        self.documents.insert(id, document.clone());
-        self.total_document_size += text.len() as u64;
+    // Adds a document read by an importer, documents without an id get
+    // a random one, ids that are already indexed are rejected
+    pub fn import(&mut self, document: CorpusDocument) -> Result<EncryptedDocument, Error> {
+        match document.id {
+            Some(id) if self.contains(id) => Err(Error::DuplicateDocument(id)),
+            Some(id) => Ok(self.insert(Document {
+                id,
+                title: document.title,
+                content: document.body,
+            })),
+            None => Ok(self.add_document(document.title, document.body)),
+        }
+    }
 
-        // get terms from text
-        let tokens = tokenize(&text);
+    // Indexes many documents at once: documents are tokenized and encrypted
+    // on all cores, then their terms are counted into the dictionary in the
+    // order of the documents, so sequence numbers are the same as if they
+    // were imported one by one. Nothing is indexed if any of the ids is taken
+    pub fn import_all(
+        &mut self,
+        documents: Vec<CorpusDocument>,
+    ) -> Result<Vec<EncryptedDocument>, Error> {
+        let mut ids = HashSet::new();
+        for id in documents.iter().filter_map(|document| document.id) {
+            if self.contains(id) || !ids.insert(id) {
+                return Err(Error::DuplicateDocument(id));
+            }
+        }
+
+        let mut rng = OsRng;
+        let documents: Vec<Document> = documents
+            .into_iter()
+            .map(|document| Document {
+                id: document.id.unwrap_or_else(|| rng.next_u64()),
+                title: document.title,
+                content: document.body,
+            })
+            .collect();
+        let indexed: Vec<(u64, TermFrequencies, EncryptedDocument)> = documents
+            .into_par_iter()
+            .map(|document| {
+                let (length, terms) = self.analyze(&document.content);
+                (length, terms, self.encrypt(&document))
+            })
+            .collect();
+
+        Ok(indexed
+            .into_iter()
+            .map(|(length, terms, encrypted)| {
+                self.index(encrypted.id, length, terms);
+                encrypted
+            })
+            .collect())
+    }
+
+    fn insert(&mut self, document: Document) -> EncryptedDocument {
+        let (length, terms) = self.analyze(&document.content);
+        let encrypted = self.encrypt(&document);
+        self.index(document.id, length, terms);
+        encrypted
+    }
 
-        // group by term and count the frequency
-        let token_freq = group_by(&tokens);
+    // Length of the text and its terms with their frequencies, sorted by
+    // the term so postings of a document are appended in a deterministic order
+    fn analyze(&self, text: &str) -> (u64, TermFrequencies) {
+        let tokens = tokenize(text);
+        let mut terms: TermFrequencies = group_by(&tokens).into_iter().collect();
+        terms.sort_unstable();
+        (self.document_length.of(text, tokens.len()), terms)
+    }
 
-        for (token, f) in token_freq.iter() {
-            let id = self.dictionary.add_or_get(token.clone());
-            let term = Term::new(token.clone(), id);
+    fn encrypt(&self, document: &Document) -> EncryptedDocument {
+        encrypt(
+            document,
+            &self.keys.document_key,
+            self.keys.epoch,
+            self.cipher,
+            self.compression,
+        )
+    }
 
-            self.index_records.push(Term2Document {
-                term,
-                freq: *f,
-                document: document.clone(),
+    // Appends a posting for each term of the document
+    fn index(&mut self, id: u64, length: u64, terms: TermFrequencies) {
+        self.total_document_size += length;
+        self.lengths.insert(id, length);
+
+        for (term, freq) in terms {
+            self.dictionary.add_or_get(term.clone());
+            // a position freed by `remove` may still be on the server
+            let position = self.postings.get(&term).map_or(0, Vec::len);
+            mark_changed(&mut self.uploaded, &term, position);
+            self.postings.entry(term).or_default().push(Posting {
+                document: id,
+                length,
+                freq,
             });
         }
-
-        document
     }
 
-    pub fn get_encrypted_doc_storage(&self) -> EncryptedDocumentStorage {
-        let mut encrypted_docs = EncryptedDocumentStorage::new();
+    // Records are encrypted on all cores and sorted by their keys,
+    // so their order tells the server nothing about terms or documents
+    pub fn get_encrypted_index(&self) -> EncryptedIndexUpdate {
+        let records = self
+            .postings
+            .par_iter()
+            .flat_map_iter(|(term, postings)| {
+                postings
+                    .iter()
+                    .enumerate()
+                    .map(move |(position, posting)| self.record(term, position, Some(posting)))
+            })
+            .collect();
+        sorted_update(records)
+    }
 
-        for document in self.documents.values() {
-            let enc_doc = encrypt(document, &self.keys.document_key);
-            encrypted_docs.add(enc_doc);
+    // Records of postings that were added or moved since `mark_uploaded`,
+    // and tombstones with an empty value for the positions the postings of
+    // a term do not reach anymore. The whole index if nothing was uploaded
+    pub fn index_changes(&self) -> EncryptedIndexUpdate {
+        let mut changes: Vec<(&str, usize)> = Vec::new();
+        for (term, postings) in &self.postings {
+            let uploaded = self.uploaded.get(term);
+            let changed = uploaded
+                .into_iter()
+                .flat_map(|uploaded| uploaded.changed.iter())
+                .map(|&position| position as usize)
+                .filter(|&position| position < postings.len());
+            let added = uploaded.map_or(0, |uploaded| uploaded.len as usize)..postings.len();
+            changes.extend(
+                changed
+                    .chain(added)
+                    .map(|position| (term.as_str(), position)),
+            );
+        }
+        for (term, uploaded) in &self.uploaded {
+            let removed = self.postings.get(term).map_or(0, Vec::len)..uploaded.len as usize;
+            changes.extend(removed.map(|position| (term.as_str(), position)));
         }
 
-        encrypted_docs
+        let records = changes
+            .into_par_iter()
+            .map(|(term, position)| {
+                let posting = self
+                    .postings
+                    .get(term)
+                    .and_then(|postings| postings.get(position));
+                self.record(term, position, posting)
+            })
+            .collect();
+        sorted_update(records)
     }
 
-    pub fn get_encrypted_index(&self) -> EncryptedIndexUpdate {
-        EncryptedIndexUpdate::insert(
-            self.index_records
-                .iter()
-                .map(|record| {
-                    let term = record.term.clone();
-                    let freq = record.freq;
-                    let document = record.document.clone();
-                    let key = encrypt_index_key(&term, &self.keys.index_key);
-                    let meta = DocumentMeta::new(document.id, document.content.len() as u64, freq);
-                    let value = encrypt_index_value(&term, &meta, &self.keys.value_key);
-
-                    EncryptedTerm2Document::new(key, value)
-                })
-                .collect(),
-        )
+    // Called once the server has the records of `index_changes`
+    pub fn mark_uploaded(&mut self) {
+        self.uploaded = self
+            .postings
+            .iter()
+            .map(|(term, postings)| {
+                let uploaded = Uploaded {
+                    len: postings.len() as u64,
+                    changed: BTreeSet::new(),
+                };
+                (term.clone(), uploaded)
+            })
+            .collect();
+    }
+
+    // Index record of the posting at `position` in the postings of the
+    // term, a tombstone with an empty value without a posting
+    fn record(&self, term: &str, position: usize, posting: Option<&Posting>) -> (Vec<u8>, Vec<u8>) {
+        let term = Term::new(term.to_string(), position as u64 + 1);
+        let key = encrypt_index_key(&term, &self.keys.index_key);
+        let value = match posting {
+            Some(posting) => {
+                let meta = DocumentMeta::new(posting.document, posting.length, posting.freq);
+                encrypt_index_value(&term, &meta, &self.keys.value_key)
+            }
+            None => Vec::new(),
+        };
+        (key, value)
     }
 }
 
+// Only positions the server has are marked, later ones are uploaded anyway
+fn mark_changed(uploaded: &mut BTreeMap<String, Uploaded>, term: &str, position: usize) {
+    if let Some(uploaded) = uploaded.get_mut(term) {
+        if (position as u64) < uploaded.len {
+            uploaded.changed.insert(position as u64);
+        }
+    }
+}
+
+fn sorted_update(mut records: Vec<(Vec<u8>, Vec<u8>)>) -> EncryptedIndexUpdate {
+    records.par_sort_unstable();
+    EncryptedIndexUpdate::insert(
+        records
+            .into_iter()
+            .map(|(key, value)| EncryptedTerm2Document::new(key, value))
+            .collect(),
+    )
+}
+
+type TermFrequencies = Vec<(String, u64)>;
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct Dictionary {
     // terms with frequencies
@@ -222,27 +638,41 @@ impl Dictionary {
     }
 }
 
+impl Default for Dictionary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::emb25::crypto::{decrypt, get_document_meta, EncryptedIndex};
+    use crate::emb25::crypto::{
+        decrypt, get_document_meta, EncryptedDocumentStorage, EncryptedIndex,
+    };
 
     #[test]
     fn test_add() {
         let mut indexer = Indexer::new();
         let text = "This is a test".to_string();
         let document = indexer.add(text);
-        assert_eq!(document.content, "This is a test");
+        assert_eq!(indexer.len(), 1);
+        assert!(indexer.contains(document.id));
+        assert_eq!(
+            indexer.decrypt(document.id, &document).unwrap().content,
+            "This is a test"
+        );
     }
 
     #[test]
-    fn test_get_encrypted_doc_storage() {
+    fn test_search_and_decrypt() {
         let mut indexer = Indexer::new();
-        let mut index = EncryptedIndex::new();
+        let index = EncryptedIndex::new();
         let text = "This is a test".to_string();
         let document = indexer.add(text);
 
-        let encrypted_doc_storage = indexer.get_encrypted_doc_storage();
+        let mut encrypted_doc_storage = EncryptedDocumentStorage::new();
+        encrypted_doc_storage.add(document.clone());
         index.update(&indexer.get_encrypted_index());
 
         // search
@@ -250,14 +680,289 @@ mod tests {
 
         let key_req = encrypt_index_key(&term, &indexer.keys.index_key);
         let val_res = index.get(&key_req).unwrap();
-        let meta = get_document_meta(&term, &val_res, &indexer.keys.value_key);
+        let meta = get_document_meta(&term, &val_res, &indexer.keys.value_key).unwrap();
 
         assert_eq!(meta.id, document.id);
 
         let decr = decrypt(
+            meta.id,
             encrypted_doc_storage.get(meta.id).unwrap(),
             &indexer.keys.document_key,
+        )
+        .unwrap();
+        assert_eq!(decr.content, "This is a test");
+        // lengths are counted in tokens
+        assert_eq!(meta.size, 4);
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        let mut indexer = Indexer::new();
+        let old_doc = indexer.add("This is a test".to_string());
+        let document = indexer.decrypt(old_doc.id, &old_doc).unwrap();
+        let old_index = indexer.get_encrypted_index();
+
+        let epoch = indexer.rotate_keys();
+        assert_eq!(epoch, 1);
+
+        let new_doc = indexer.reencrypt(document.id, &old_doc).unwrap();
+        assert_eq!(new_doc.epoch, 1);
+        assert_ne!(new_doc.ciphertext, old_doc.ciphertext);
+
+        // index entries are re-derived from the new keys
+        let index = EncryptedIndex::with_epoch(epoch);
+        index.update(&indexer.get_encrypted_index());
+        assert_ne!(indexer.get_encrypted_index(), old_index);
+        let query = indexer.query("test".to_string());
+        let meta = indexer
+            .meta(&query.terms[0], &index.get(&query.query[0]).unwrap())
+            .unwrap();
+        assert_eq!(meta.id, document.id);
+
+        assert_eq!(indexer.decrypt(document.id, &new_doc).unwrap(), document);
+        assert_eq!(indexer.decrypt(document.id, &old_doc).unwrap(), document);
+
+        // keys of earlier epochs are derived again after a restart, epochs
+        // the indexer has not rotated to yet are rejected
+        let master_key = indexer.master_key.clone();
+        let restored = Indexer::restore(master_key.clone(), indexer.snapshot());
+        assert_eq!(restored.decrypt(document.id, &old_doc).unwrap(), document);
+        let mut ahead = Indexer::restore(master_key, indexer.snapshot());
+        ahead.rotate_keys();
+        let future_doc = ahead.reencrypt(document.id, &old_doc).unwrap();
+        assert_eq!(
+            restored.decrypt(document.id, &future_doc),
+            Err(Error::UnknownEpoch(2))
+        );
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let master_key = MasterKey::generate();
+        let mut indexer = Indexer::with_collection("notes", master_key.clone());
+        let document = indexer.add_document("title".to_string(), "brown fox".to_string());
+
+        let state = indexer.snapshot();
+        assert_eq!(state.lengths, HashMap::from([(document.id, 2)]));
+        let restored = Indexer::restore(master_key, state);
+        assert_eq!(restored.collection(), "notes");
+        assert_eq!(
+            restored.get_encrypted_index(),
+            indexer.get_encrypted_index()
+        );
+        assert_eq!(
+            restored.decrypt(document.id, &document).unwrap().title,
+            "title"
+        );
+    }
+
+    #[test]
+    fn test_remove_uploads_changes() {
+        let mut indexer = Indexer::new();
+        let first = indexer.add("brown fox".to_string());
+        let second = indexer.add("lazy fox".to_string());
+        assert_eq!(indexer.dictionary.freq("fox"), Some(&2));
+        assert_eq!(indexer.index_changes(), indexer.get_encrypted_index());
+        let index = EncryptedIndex::new();
+        index.update(&indexer.index_changes());
+        indexer.mark_uploaded();
+        assert!(indexer.index_changes().is_empty());
+
+        assert!(indexer.remove(first.id));
+        assert!(!indexer.remove(first.id));
+        assert_eq!(indexer.len(), 1);
+        assert_eq!(indexer.dictionary.freq("fox"), Some(&1));
+        assert_eq!(indexer.dictionary.freq("brown"), None);
+
+        // "fox" 1 is rewritten, "fox" 2 and "brown" 1 are dropped
+        let changes = indexer.index_changes();
+        assert_eq!(changes.len(), 3);
+        index.update(&changes);
+        assert_eq!(index.len(), 2);
+
+        // the remaining document is found under sequence number 1
+        let query = indexer.query("fox".to_string());
+        assert_eq!(query.terms.len(), 1);
+        let meta = indexer
+            .meta(&query.terms[0], &index.get(&query.query[0]).unwrap())
+            .unwrap();
+        assert_eq!(meta.id, second.id);
+
+        // a posting added before the next upload takes the dropped position
+        let third = indexer.add("red fox".to_string());
+        index.update(&indexer.index_changes());
+        indexer.mark_uploaded();
+        assert_eq!(index.len(), 4);
+        let query = indexer.query("fox".to_string());
+        let meta = indexer
+            .meta(&query.terms[1], &index.get(&query.query[1]).unwrap())
+            .unwrap();
+        assert_eq!(meta.id, third.id);
+    }
+
+    #[test]
+    fn test_import_keeps_ids() {
+        let mut indexer = Indexer::new();
+        let document = CorpusDocument {
+            id: Some(42),
+            title: "fox".to_string(),
+            body: "brown fox".to_string(),
+        };
+        let imported = indexer.import(document.clone()).unwrap();
+        assert_eq!(imported.id, 42);
+        assert_eq!(indexer.import(document), Err(Error::DuplicateDocument(42)));
+
+        let random = indexer
+            .import(CorpusDocument {
+                id: None,
+                title: String::new(),
+                body: "lazy dog".to_string(),
+            })
+            .unwrap();
+        assert_ne!(random.id, 42);
+        assert_eq!(indexer.len(), 2);
+        assert_eq!(indexer.dictionary.freq("fox"), Some(&1));
+    }
+
+    #[test]
+    fn test_import_all_matches_import() {
+        let documents: Vec<CorpusDocument> = (0..50)
+            .map(|i| CorpusDocument {
+                id: Some(i),
+                title: String::new(),
+                body: format!("fox {} dog {} fox", i % 7, i % 3),
+            })
+            .collect();
+
+        let mut sequential = Indexer::new();
+        for document in documents.iter().cloned() {
+            sequential.import(document).unwrap();
+        }
+        let mut parallel = Indexer::new();
+        parallel.import_all(documents.clone()).unwrap();
+
+        assert_eq!(parallel.dictionary, sequential.dictionary);
+        assert_eq!(parallel.snapshot().postings, sequential.snapshot().postings);
+        assert_eq!(
+            parallel.import_all(documents[..1].to_vec()),
+            Err(Error::DuplicateDocument(0))
+        );
+        assert_eq!(parallel.len(), 50);
+    }
+
+    // Plaintext BM25 over the same tokens, with the parameters of `Indexer::bm25`
+    fn reference_bm25(corpus: &[(u64, &str)], query: &str) -> Vec<(u64, f64)> {
+        let documents: Vec<(u64, Vec<String>)> = corpus
+            .iter()
+            .map(|(id, text)| (*id, tokenize(text)))
+            .collect();
+        let n = documents.len() as f64;
+        let avgdl = documents.iter().map(|(_, t)| t.len()).sum::<usize>() as f64 / n;
+        let (k1, b) = (1.2, 0.75);
+
+        let mut ranked = Vec::new();
+        for (id, tokens) in documents.iter() {
+            let mut score = 0.;
+            for (term, _) in group_by(&tokenize(query)) {
+                let df = documents.iter().filter(|(_, t)| t.contains(&term)).count() as f64;
+                let tf = tokens.iter().filter(|t| **t == term).count() as f64;
+                if tf == 0. {
+                    continue;
+                }
+                let idf = (1. + (n - df + 0.5) / (df + 0.5)).ln();
+                let dl = tokens.len() as f64;
+                score += idf * tf * (k1 + 1.) / (tf + k1 * (1. - b + b * dl / avgdl));
+            }
+            if score > 0. {
+                ranked.push((*id, score));
+            }
+        }
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+
+    fn search(indexer: &Indexer, index: &EncryptedIndex, text: &str) -> Vec<(u64, f64)> {
+        let query = indexer.query(text.to_string());
+        let values: Vec<Vec<u8>> = query
+            .query
+            .iter()
+            .map(|key| index.get(key).unwrap_or_default())
+            .collect();
+        indexer.rank(&query, &values)
+    }
+
+    #[test]
+    fn test_rank_matches_reference_bm25() {
+        let corpus = [
+            (1, "the quick brown fox jumps over the lazy dog"),
+            (2, "the lazy dog sleeps"),
+            (3, "a fox and another fox and a third fox"),
+            (4, "brown bread and brown butter for the brown bear"),
+            (5, "quick quick quick"),
+            (6, "nothing to see here at all, really nothing"),
+        ];
+        let mut indexer = Indexer::new();
+        for (id, text) in corpus.iter() {
+            indexer
+                .import(CorpusDocument {
+                    id: Some(*id),
+                    title: String::new(),
+                    body: text.to_string(),
+                })
+                .unwrap();
+        }
+        let index = EncryptedIndex::new();
+        index.update(&indexer.get_encrypted_index());
+
+        for query in ["fox", "brown fox", "the lazy dog", "quick brown", "missing"] {
+            let ranked = search(&indexer, &index, query);
+            let expected = reference_bm25(&corpus, query);
+            assert_eq!(
+                ranked.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                expected.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                "order of {:?}",
+                query
+            );
+            for ((_, score), (_, reference)) in ranked.iter().zip(expected.iter()) {
+                assert!((score - reference).abs() < 1e-9, "scores of {:?}", query);
+            }
+        }
+
+        // postings that were not flushed yet have no values on the server
+        let query = indexer.query("fox".to_string());
+        assert!(indexer
+            .rank(&query, &vec![Vec::new(); query.query.len()])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_non_ascii_text_is_not_penalized() {
+        let mut tokens = Indexer::new();
+        let mut bytes = Indexer::new();
+        bytes.set_document_length(DocumentLength::Bytes).unwrap();
+        let index = |indexer: &mut Indexer| {
+            let ascii = indexer.add("fox river stone".to_string()).id;
+            let greek = indexer.add("fox ποταμός πέτρα".to_string()).id;
+            let index = EncryptedIndex::new();
+            index.update(&indexer.get_encrypted_index());
+            (ascii, greek, search(indexer, &index, "fox"))
+        };
+
+        // both documents have three tokens and score the same
+        let (_, _, ranked) = index(&mut tokens);
+        assert_eq!(ranked.len(), 2);
+        assert!((ranked[0].1 - ranked[1].1).abs() < 1e-12);
+
+        // the Greek document is twice as long in bytes
+        let (ascii, greek, ranked) = index(&mut bytes);
+        assert_eq!(ranked[0].0, ascii);
+        assert_eq!(ranked[1].0, greek);
+        assert!(ranked[0].1 > ranked[1].1);
+
+        // lengths of indexed documents would be mixed up
+        assert_eq!(
+            bytes.set_document_length(DocumentLength::Tokens),
+            Err(Error::IndexNotEmpty)
         );
-        assert_eq!(decr.content, document.content)
     }
 }
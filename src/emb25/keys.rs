@@ -0,0 +1,238 @@
+use crate::emb25::crypto::{KeyEpoch, SymmetricKey};
+use crate::emb25::error::Error;
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+
+pub const KEY_SIZE: usize = 32;
+
+// Labels used to derive independent keys from the master key, every
+// label is bound to the key epoch and the collection, see `Keys::derive`
+const INDEX_KEY_LABEL: &[u8] = b"ebm25/v1/index-key";
+const VALUE_KEY_LABEL: &[u8] = b"ebm25/v1/value-key";
+const DOCUMENT_KEY_LABEL: &[u8] = b"ebm25/v1/document-key";
+const VERIFICATION_TAG_LABEL: &[u8] = b"ebm25/v1/verification-tag";
+
+const SALT_SIZE: usize = 16;
+
+// The only secret the client has to keep, all other
+// keys are derived from it with HKDF-SHA3-256
+#[derive(Clone, PartialEq)]
+pub struct MasterKey {
+    key: [u8; KEY_SIZE],
+}
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+
+    pub fn from_bytes(key: [u8; KEY_SIZE]) -> Self {
+        Self { key }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.key
+    }
+
+    // Derives a master key from a passphrase with a fresh salt and the
+    // default Argon2id cost, returned parameters must be stored by the
+    // client to derive the same key again with `unlock`
+    pub fn from_passphrase(passphrase: &str) -> Result<(Self, PassphraseParams), Error> {
+        Self::from_passphrase_with_cost(
+            passphrase,
+            Params::DEFAULT_M_COST,
+            Params::DEFAULT_T_COST,
+            Params::DEFAULT_P_COST,
+        )
+    }
+
+    pub fn from_passphrase_with_cost(
+        passphrase: &str,
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> Result<(Self, PassphraseParams), Error> {
+        let mut salt = vec![0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let mut params = PassphraseParams {
+            salt,
+            memory_kib,
+            iterations,
+            parallelism,
+            verification_tag: Vec::new(),
+        };
+        let master = params.derive(passphrase)?;
+        params.verification_tag = master.verification_tag().to_vec();
+        Ok((master, params))
+    }
+
+    // Derives the master key again and checks it against the stored
+    // verification tag, so a wrong passphrase is reported here instead
+    // of queries that silently match nothing
+    pub fn unlock(passphrase: &str, params: &PassphraseParams) -> Result<Self, Error> {
+        let master = params.derive(passphrase)?;
+        if master.verification_tag()[..] != params.verification_tag[..] {
+            return Err(Error::WrongPassphrase);
+        }
+        Ok(master)
+    }
+
+    fn verification_tag(&self) -> [u8; KEY_SIZE] {
+        let hkdf = Hkdf::<Sha3_256>::new(None, &self.key);
+        let mut tag = [0u8; KEY_SIZE];
+        hkdf.expand(VERIFICATION_TAG_LABEL, &mut tag)
+            .expect("32 bytes is a valid HKDF output length");
+        tag
+    }
+}
+
+// Argon2id parameters of a passphrase derived master key, they are
+// not secret and can be stored next to the client state
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PassphraseParams {
+    pub salt: Vec<u8>,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub verification_tag: Vec<u8>,
+}
+
+impl PassphraseParams {
+    fn derive(&self, passphrase: &str) -> Result<MasterKey, Error> {
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            Some(KEY_SIZE),
+        )
+        .map_err(|e| Error::InvalidKdfParams(e.to_string()))?;
+
+        let mut key = [0u8; KEY_SIZE];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| Error::InvalidKdfParams(e.to_string()))?;
+        Ok(MasterKey::from_bytes(key))
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Keys {
+    pub(crate) epoch: KeyEpoch,
+    pub(crate) document_key: SymmetricKey,
+    pub(crate) index_key: Vec<u8>,
+    pub(crate) value_key: Vec<u8>,
+}
+
+impl Keys {
+    // Collections sharing a master key get unrelated keys,
+    // so the server cannot link their index entries
+    pub fn derive(master: &MasterKey, collection: &str, epoch: KeyEpoch) -> Self {
+        let hkdf = Hkdf::<Sha3_256>::new(None, master.as_bytes());
+        let expand = |label| expand(&hkdf, label, collection, epoch);
+        Self {
+            epoch,
+            document_key: SymmetricKey::from_bytes(&expand(DOCUMENT_KEY_LABEL)),
+            index_key: expand(INDEX_KEY_LABEL).to_vec(),
+            value_key: expand(VALUE_KEY_LABEL).to_vec(),
+        }
+    }
+}
+
+// HKDF info is label || epoch (u32 BE) || collection, the
+// label and the epoch have fixed length so it is unambiguous
+fn expand(
+    hkdf: &Hkdf<Sha3_256>,
+    label: &[u8],
+    collection: &str,
+    epoch: KeyEpoch,
+) -> [u8; KEY_SIZE] {
+    let mut info = label.to_vec();
+    info.extend_from_slice(&epoch.to_be_bytes());
+    info.extend_from_slice(collection.as_bytes());
+
+    let mut okm = [0u8; KEY_SIZE];
+    hkdf.expand(&info, &mut okm)
+        .expect("32 bytes is a valid HKDF output length");
+    okm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn test_derive_vectors() {
+        let master = MasterKey::from_bytes([7u8; KEY_SIZE]);
+        let keys = Keys::derive(&master, "default", 0);
+
+        assert_eq!(
+            keys.index_key,
+            hex!("80542c90aae20121766a57598a5fb15a916bc474e0ada0911ff026bd5c5d362e")
+        );
+        assert_eq!(
+            keys.value_key,
+            hex!("5f52d517003cbdbbbd339576ae0a74c5202db57803035f4e02fcff3f85174422")
+        );
+        assert_eq!(
+            keys.document_key.as_bytes(),
+            hex!("9384604ec3176bdb1fe89a0641c1a04a3253545527d11c32dc6ac50d5d839572")
+        );
+    }
+
+    #[test]
+    fn test_derive_separates_epochs() {
+        let master = MasterKey::from_bytes([7u8; KEY_SIZE]);
+        let k0 = Keys::derive(&master, "default", 0);
+        let k1 = Keys::derive(&master, "default", 1);
+
+        assert_ne!(k0.index_key, k1.index_key);
+        assert_ne!(k0.value_key, k1.value_key);
+        assert_ne!(k0.index_key, k0.value_key);
+    }
+
+    #[test]
+    fn test_derive_separates_collections() {
+        let master = MasterKey::from_bytes([7u8; KEY_SIZE]);
+        let a = Keys::derive(&master, "a", 0);
+        let b = Keys::derive(&master, "b", 0);
+
+        assert_ne!(a.index_key, b.index_key);
+        assert_ne!(a.value_key, b.value_key);
+        assert_ne!(a.document_key.as_bytes(), b.document_key.as_bytes());
+    }
+
+    #[test]
+    fn test_passphrase_unlock() {
+        let (master, params) =
+            MasterKey::from_passphrase_with_cost("correct horse", 64, 1, 1).unwrap();
+
+        let unlocked = MasterKey::unlock("correct horse", &params).unwrap();
+        assert!(unlocked == master);
+
+        let wrong = MasterKey::unlock("battery staple", &params);
+        assert!(matches!(wrong, Err(Error::WrongPassphrase)));
+    }
+
+    #[test]
+    fn test_passphrase_vector() {
+        let params = PassphraseParams {
+            salt: b"0123456789abcdef".to_vec(),
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+            verification_tag: Vec::new(),
+        };
+        let master = params.derive("correct horse").unwrap();
+        assert_eq!(
+            master.as_bytes(),
+            &hex!("32364fd7ed54f4951b0d6bc3bed2f212c66235738a114c5f8ba3b6a1ee3abb10")
+        );
+    }
+}
@@ -1,12 +1,34 @@
+mod cipher;
+mod compression;
+mod corpus;
 mod crypto;
+mod error;
+mod evaluation;
 mod index;
 mod indexer;
+mod keys;
 mod utils;
+mod wire;
 
+pub use cipher::CipherAlgorithm;
+pub use compression::Compression;
+pub use corpus::{
+    read_text_dir, read_text_file, write_json_lines, CorpusDocument, JsonFields, JsonLinesReader,
+};
 pub use crypto::{
     DocumentMeta, EncryptedDocument, EncryptedDocumentStorage, EncryptedIndex,
-    EncryptedIndexUpdate, EncryptedTerm2Document,
+    EncryptedIndexUpdate, EncryptedTerm2Document, KeyEpoch, INDEX_VALUE_SIZE,
+};
+pub use error::Error;
+pub use evaluation::{read_queries, Evaluation, Metrics, Qrels};
+pub use index::{Document, Term};
+pub use indexer::{
+    Dictionary, DocumentLength, Indexer, IndexerState, Posting, Postings, Query, BM25,
+    DEFAULT_COLLECTION,
+};
+pub use keys::{MasterKey, PassphraseParams};
+pub use utils::{analyzer, group_by, tokenize};
+pub use wire::{
+    CollectionStats, IngestReport, RecordDecoder, WireFormat, BINARY_CONTENT_TYPE,
+    JSON_CONTENT_TYPE,
 };
-pub use index::{Document, IndexUpdate, Term, Term2Document};
-pub use indexer::{Indexer, Query, BM25};
-pub use utils::{group_by, tokenize};
@@ -1,11 +1,17 @@
 use std::collections::HashMap;
 use tantivy::tokenizer::*;
 
-pub fn tokenize(text: &str) -> Vec<String> {
-    let mut en_stem = TextAnalyzer::builder(SimpleTokenizer::default())
+// Analyzer of documents and queries, it can be registered
+// with a tantivy index to tokenize text the same way
+pub fn analyzer() -> TextAnalyzer {
+    TextAnalyzer::builder(SimpleTokenizer::default())
         .filter(RemoveLongFilter::limit(40))
         .filter(Stemmer::new(Language::English))
-        .build();
+        .build()
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut en_stem = analyzer();
     let mut tokens = Vec::new();
     en_stem.token_stream(text).process(&mut |token| {
         tokens.push(token.text.clone());
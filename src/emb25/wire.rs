@@ -0,0 +1,341 @@
+use crate::emb25::crypto::KeyEpoch;
+use crate::emb25::error::Error;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const BINARY_CONTENT_TYPE: &str = "application/x-bincode";
+
+// Length prefix of a binary record in a stream
+const FRAME_HEADER: usize = 4;
+
+// Records are small (an index record is under 100 bytes), the limit keeps
+// a broken or malicious stream from growing the buffer without bound
+pub const MAX_RECORD_SIZE: usize = 1024 * 1024;
+
+// Encoding of requests and responses between the client and the server.
+// JSON writes every byte of keys and ciphertexts as a decimal number,
+// the binary format writes them as length-prefixed raw bytes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    Json,
+    #[default]
+    Binary,
+}
+
+impl WireFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            WireFormat::Json => JSON_CONTENT_TYPE,
+            WireFormat::Binary => BINARY_CONTENT_TYPE,
+        }
+    }
+
+    // Parameters such as charset are ignored
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if mime.eq_ignore_ascii_case(JSON_CONTENT_TYPE) {
+            Some(WireFormat::Json)
+        } else if mime.eq_ignore_ascii_case(BINARY_CONTENT_TYPE) {
+            Some(WireFormat::Binary)
+        } else {
+            None
+        }
+    }
+
+    // Picks the format from an Accept header, JSON is the fallback
+    // so the server stays usable from curl and browsers
+    pub fn from_accept(accept: &str) -> Self {
+        accept
+            .split(',')
+            .filter_map(Self::from_content_type)
+            .next()
+            .unwrap_or(WireFormat::Json)
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Vec<u8> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(value).unwrap(),
+            WireFormat::Binary => bincode::DefaultOptions::new().serialize(value).unwrap(),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, Error> {
+        match self {
+            WireFormat::Json => {
+                serde_json::from_slice(bytes).map_err(|e| Error::MalformedMessage(e.to_string()))
+            }
+            WireFormat::Binary => bincode::DefaultOptions::new()
+                .deserialize(bytes)
+                .map_err(|e| Error::MalformedMessage(e.to_string())),
+        }
+    }
+}
+
+impl WireFormat {
+    // Encodes a single record of a stream: binary records are prefixed
+    // with u32 BE length, JSON records are newline delimited (NDJSON)
+    pub fn encode_record<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        let encoded = self.encode(value);
+        match self {
+            WireFormat::Json => {
+                let mut line = encoded;
+                line.push(b'\n');
+                line
+            }
+            WireFormat::Binary => {
+                let mut frame = Vec::with_capacity(FRAME_HEADER + encoded.len());
+                frame.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+                frame.extend_from_slice(&encoded);
+                frame
+            }
+        }
+    }
+}
+
+// Incremental decoder of a stream of records, chunks of the body
+// are pushed as they arrive and complete records are taken out,
+// so the whole body never has to be kept in memory
+pub struct RecordDecoder {
+    format: WireFormat,
+    buffer: Vec<u8>,
+    // start of the first record that was not taken yet
+    position: usize,
+}
+
+impl RecordDecoder {
+    pub fn new(format: WireFormat) -> Self {
+        Self {
+            format,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buffer.drain(..self.position);
+        self.position = 0;
+        self.buffer.extend_from_slice(chunk);
+    }
+
+    // Returns the next complete record, or None if more data is needed
+    pub fn next_record<T: DeserializeOwned>(&mut self) -> Option<Result<T, Error>> {
+        loop {
+            let rest = &self.buffer[self.position..];
+            let (record, consumed) = match self.format {
+                WireFormat::Json => match rest.iter().position(|b| *b == b'\n') {
+                    Some(newline) => (&rest[..newline], newline + 1),
+                    None if rest.len() > MAX_RECORD_SIZE => return Some(Err(too_large())),
+                    None => return None,
+                },
+                WireFormat::Binary => {
+                    let header = rest.get(..FRAME_HEADER)?;
+                    let len = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+                    if len > MAX_RECORD_SIZE {
+                        return Some(Err(too_large()));
+                    }
+                    let record = rest.get(FRAME_HEADER..FRAME_HEADER + len)?;
+                    (record, FRAME_HEADER + len)
+                }
+            };
+            self.position += consumed;
+
+            // blank lines between JSON records are skipped
+            if self.format == WireFormat::Json && record.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            return Some(self.format.decode(record));
+        }
+    }
+
+    // Called at the end of the stream, the last JSON record may
+    // be not terminated with a newline, any other leftover is an error
+    pub fn finish<T: DeserializeOwned>(&mut self) -> Result<Option<T>, Error> {
+        let rest = &self.buffer[self.position..];
+        self.position = self.buffer.len();
+        if rest.iter().all(u8::is_ascii_whitespace) {
+            return Ok(None);
+        }
+        match self.format {
+            WireFormat::Json => self.format.decode(rest).map(Some),
+            WireFormat::Binary => Err(Error::MalformedMessage(format!(
+                "stream ends with {} bytes of an incomplete record",
+                rest.len()
+            ))),
+        }
+    }
+}
+
+fn too_large() -> Error {
+    Error::MalformedMessage(format!("record exceeds {} bytes", MAX_RECORD_SIZE))
+}
+
+// Counters reported back to the client after a streamed upload
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct IngestReport {
+    // records received in this request
+    pub records: u64,
+    // number of batches they were applied in
+    pub batches: u64,
+    // index size after the upload
+    pub total: u64,
+}
+
+// State of a collection reported by the collection endpoints
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CollectionStats {
+    pub name: String,
+    // key epoch of the index and documents served to searches
+    pub epoch: KeyEpoch,
+    pub documents: u64,
+    pub index_entries: u64,
+    // nonces and ciphertexts of the documents
+    pub document_bytes: u64,
+    // keys and values of the index entries
+    pub index_bytes: u64,
+    // milliseconds since the UNIX epoch, none if nothing was stored yet
+    pub last_update_ms: Option<u64>,
+    // epoch of a key rotation that was started but not committed yet
+    pub staged_epoch: Option<KeyEpoch>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CipherAlgorithm, Compression, EncryptedDocument, EncryptedIndexUpdate};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_binary_is_compact() {
+        let mut update = EncryptedIndexUpdate::new();
+        for i in 0..100u8 {
+            update.add(vec![i; 32], vec![255 - i; 24]);
+        }
+
+        let json = WireFormat::Json.encode(&update);
+        let binary = WireFormat::Binary.encode(&update);
+        assert!(binary.len() * 3 < json.len());
+
+        let decoded: EncryptedIndexUpdate = WireFormat::Binary.decode(&binary).unwrap();
+        assert_eq!(decoded, update);
+        let decoded: EncryptedIndexUpdate = WireFormat::Json.decode(&json).unwrap();
+        assert_eq!(decoded, update);
+    }
+
+    #[test]
+    fn test_negotiation() {
+        assert_eq!(
+            WireFormat::from_content_type("application/json; charset=utf-8"),
+            Some(WireFormat::Json)
+        );
+        assert_eq!(WireFormat::from_content_type("text/plain"), None);
+        assert_eq!(
+            WireFormat::from_accept("text/html, application/x-bincode"),
+            WireFormat::Binary
+        );
+        assert_eq!(WireFormat::from_accept("*/*"), WireFormat::Json);
+    }
+
+    #[test]
+    fn test_malformed_binary() {
+        let decoded: Result<Vec<Vec<u8>>, Error> = WireFormat::Binary.decode(&[0xff, 0xff]);
+        assert!(matches!(decoded, Err(Error::MalformedMessage(_))));
+    }
+
+    #[test]
+    fn test_record_stream_split_chunks() {
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let mut body = Vec::new();
+            for i in 0..10u8 {
+                body.extend(format.encode_record(&vec![i; 3]));
+            }
+
+            // feed the body byte by byte, so every record is split between chunks
+            let mut decoder = RecordDecoder::new(format);
+            let mut records: Vec<Vec<u8>> = Vec::new();
+            for b in body {
+                decoder.push(&[b]);
+                while let Some(record) = decoder.next_record() {
+                    records.push(record.unwrap());
+                }
+            }
+            assert_eq!(decoder.finish::<Vec<u8>>().unwrap(), None);
+            assert_eq!(records.len(), 10);
+            assert_eq!(records[9], vec![9u8; 3]);
+        }
+    }
+
+    #[test]
+    fn test_record_stream_leftovers() {
+        let mut decoder = RecordDecoder::new(WireFormat::Json);
+        decoder.push(b"[1,2]\n\n[3]");
+        assert_eq!(
+            decoder.next_record::<Vec<u8>>().unwrap().unwrap(),
+            vec![1, 2]
+        );
+        assert!(decoder.next_record::<Vec<u8>>().is_none());
+        assert_eq!(decoder.finish::<Vec<u8>>().unwrap(), Some(vec![3]));
+
+        let mut decoder = RecordDecoder::new(WireFormat::Binary);
+        decoder.push(&[0, 0, 0, 9, 1]);
+        assert!(decoder.next_record::<Vec<u8>>().is_none());
+        assert!(decoder.finish::<Vec<u8>>().is_err());
+
+        let mut decoder = RecordDecoder::new(WireFormat::Binary);
+        decoder.push(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(decoder.next_record::<Vec<u8>>().unwrap().is_err());
+    }
+
+    fn format() -> impl Strategy<Value = WireFormat> {
+        prop_oneof![Just(WireFormat::Json), Just(WireFormat::Binary)]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_document_roundtrip(
+            format in format(),
+            id: u64,
+            epoch: KeyEpoch,
+            nonce in prop::collection::vec(any::<u8>(), 0..32),
+            ciphertext in prop::collection::vec(any::<u8>(), 0..256),
+        ) {
+            let document = EncryptedDocument {
+                id,
+                epoch,
+                algorithm: CipherAlgorithm::XChaCha20Poly1305,
+                compression: Compression::Zstd,
+                nonce,
+                ciphertext,
+            };
+            let decoded: EncryptedDocument = format.decode(&format.encode(&document)).unwrap();
+            prop_assert_eq!(decoded, document);
+        }
+
+        // records come out whole however the body is split into chunks
+        #[test]
+        fn prop_record_stream_roundtrip(
+            format in format(),
+            records in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64), 0..20),
+            cuts in prop::collection::vec(any::<usize>(), 0..10),
+        ) {
+            let body: Vec<u8> = records.iter().flat_map(|r| format.encode_record(r)).collect();
+            let mut cuts: Vec<usize> = cuts.iter().map(|c| c % (body.len() + 1)).collect();
+            cuts.push(0);
+            cuts.push(body.len());
+            cuts.sort_unstable();
+
+            let mut decoder = RecordDecoder::new(format);
+            let mut decoded: Vec<Vec<u8>> = Vec::new();
+            for chunk in cuts.windows(2) {
+                decoder.push(&body[chunk[0]..chunk[1]]);
+                while let Some(record) = decoder.next_record() {
+                    decoded.push(record.unwrap());
+                }
+            }
+            prop_assert_eq!(decoder.finish::<Vec<u8>>().unwrap(), None);
+            prop_assert_eq!(decoded, records);
+        }
+    }
+}
@@ -1,3 +1,4 @@
 mod emb25;
+pub mod server;
 
 pub use emb25::*;
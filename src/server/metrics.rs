@@ -0,0 +1,121 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+// Route label of requests that did not match any route, paths
+// themselves are never used as labels, they contain collection names
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// Prometheus metrics of the server. Request metrics are labelled with the
+// route pattern, sizes are aggregated per tenant and set on every scrape
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    collections: IntGaugeVec,
+    documents: IntGaugeVec,
+    index_entries: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("ebm25".to_string()), None).expect("namespace is valid");
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of handled requests"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time to handle a request"),
+            &["method", "route"],
+        )
+        .unwrap();
+        let collections = IntGaugeVec::new(
+            Opts::new("collections", "Number of collections"),
+            &["tenant"],
+        )
+        .unwrap();
+        let documents = IntGaugeVec::new(
+            Opts::new("documents", "Number of stored documents"),
+            &["tenant"],
+        )
+        .unwrap();
+        let index_entries = IntGaugeVec::new(
+            Opts::new("index_entries", "Number of encrypted index entries"),
+            &["tenant"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(collections.clone())).unwrap();
+        registry.register(Box::new(documents.clone())).unwrap();
+        registry.register(Box::new(index_entries.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            latency,
+            collections,
+            documents,
+            index_entries,
+        }
+    }
+
+    pub fn observe(&self, method: &str, route: &str, status: u16, seconds: f64) {
+        self.requests
+            .with_label_values(&[method, route, &status.to_string()])
+            .inc();
+        self.latency
+            .with_label_values(&[method, route])
+            .observe(seconds);
+    }
+
+    pub fn set_tenant(&self, tenant: &str, collections: u64, documents: u64, index_entries: u64) {
+        self.collections
+            .with_label_values(&[tenant])
+            .set(collections as i64);
+        self.documents
+            .with_label_values(&[tenant])
+            .set(documents as i64);
+        self.index_entries
+            .with_label_values(&[tenant])
+            .set(index_entries as i64);
+    }
+
+    // Metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics are encodable");
+        String::from_utf8(buffer).expect("text format is UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.observe("POST", "/collections/{collection}/index/search", 200, 0.002);
+        metrics.set_tenant("search", 2, 10, 120);
+
+        let text = metrics.render();
+        assert!(text.contains(
+            "ebm25_http_requests_total{method=\"POST\",route=\"/collections/{collection}/index/search\",status=\"200\"} 1"
+        ));
+        assert!(text.contains("ebm25_http_request_duration_seconds_count"));
+        assert!(text.contains("ebm25_documents{tenant=\"search\"} 10"));
+        assert!(text.contains("ebm25_index_entries{tenant=\"search\"} 120"));
+    }
+}
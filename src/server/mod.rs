@@ -0,0 +1,1283 @@
+mod metrics;
+mod tenant;
+
+use crate::{
+    CollectionStats, EncryptedDocument, EncryptedDocumentStorage, EncryptedIndex,
+    EncryptedIndexUpdate, EncryptedTerm2Document, IngestReport, KeyEpoch, RecordDecoder,
+    WireFormat,
+};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    ETag, EntityTag, IfMatch, ACCEPT, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE,
+};
+use actix_web::http::StatusCode;
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{error, web, App, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder};
+use futures_util::future::{ready, Ready};
+use futures_util::StreamExt;
+use metrics::UNMATCHED_ROUTE;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+pub use metrics::Metrics;
+pub use tenant::{is_valid_name, TenantConfig};
+
+// Number of records of a streamed upload that are buffered
+// before they are inserted into the index
+const STREAM_BATCH_SIZE: usize = 10_000;
+
+// This is an encrypted server state, every tenant has its own
+// collections and can only access them with its API token
+pub struct ServerState {
+    // SHA3-256 of API token -> tenant name, raw tokens are not kept
+    tokens: HashMap<[u8; 32], String>,
+    // SHA3-256 of the admin token, the monitoring routes
+    // are not served at all if none is configured
+    admin: Option<[u8; 32]>,
+    tenants: HashMap<String, Tenant>,
+}
+
+// Collections are created and dropped by the tenant, their
+// names only have to be unique among collections of the tenant
+#[derive(Default)]
+struct Tenant {
+    collections: RwLock<HashMap<String, Arc<Collection>>>,
+}
+
+// Search index and document storage of one collection,
+// all of them are encrypted with the same keys. Locks that are
+// held together are taken in the order rotation, storage, index
+struct Collection {
+    storage: RwLock<EncryptedDocumentStorage>,
+    // the index is updated in place through a shared reference, the
+    // lock is only taken for writing to swap it on key rotation
+    index: RwLock<Arc<EncryptedIndex>>,
+    rotation: Mutex<Option<Rotation>>,
+}
+
+// Index and documents of the key epoch that is being uploaded
+// during key rotation, they are not visible to searches until
+// the rotation is committed
+struct Rotation {
+    index: EncryptedIndex,
+    storage: EncryptedDocumentStorage,
+}
+
+impl Rotation {
+    fn new(epoch: KeyEpoch) -> Self {
+        Self {
+            index: EncryptedIndex::with_epoch(epoch),
+            storage: EncryptedDocumentStorage::new(),
+        }
+    }
+}
+
+impl ServerState {
+    pub fn new(tenants: &[TenantConfig]) -> Self {
+        Self {
+            tokens: tenants
+                .iter()
+                .map(|t| (token_digest(&t.token), t.name.clone()))
+                .collect(),
+            admin: None,
+            tenants: tenants
+                .iter()
+                .map(|t| (t.name.clone(), Tenant::default()))
+                .collect(),
+        }
+    }
+
+    // Token of the operator, it is required by the monitoring
+    // routes that report on all tenants
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin = Some(token_digest(token));
+        self
+    }
+
+    fn tenant(&self, token: &str) -> Option<&str> {
+        self.tokens.get(&token_digest(token)).map(String::as_str)
+    }
+
+    fn is_admin(&self, token: &str) -> bool {
+        self.admin == Some(token_digest(token))
+    }
+
+    // Stats of every collection with the name of its tenant
+    fn collection_stats(&self) -> Vec<(&str, CollectionStats)> {
+        let mut stats = Vec::new();
+        for (tenant_name, tenant) in &self.tenants {
+            let collections = tenant.collections.read().unwrap();
+            stats.extend(
+                collections
+                    .iter()
+                    .map(|(name, collection)| (tenant_name.as_str(), collection.stats(name))),
+            );
+        }
+        stats
+    }
+
+    // A handler that panicked while holding a lock leaves it poisoned,
+    // every later request to the collection would fail
+    fn is_ready(&self) -> bool {
+        self.tenants
+            .values()
+            .all(|tenant| match tenant.collections.read() {
+                Ok(collections) => collections.values().all(|c| c.is_healthy()),
+                Err(_) => false,
+            })
+    }
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl Tenant {
+    fn collection(&self, name: &str) -> Option<Arc<Collection>> {
+        self.collections.read().unwrap().get(name).cloned()
+    }
+}
+
+fn token_digest(token: &str) -> [u8; 32] {
+    Sha3_256::digest(token.as_bytes()).into()
+}
+
+impl Collection {
+    fn new(epoch: KeyEpoch) -> Self {
+        Self {
+            storage: RwLock::new(EncryptedDocumentStorage::new()),
+            index: RwLock::new(Arc::new(EncryptedIndex::with_epoch(epoch))),
+            rotation: Mutex::new(None),
+        }
+    }
+
+    // Index of the active key epoch
+    fn index(&self) -> Arc<EncryptedIndex> {
+        self.index.read().unwrap().clone()
+    }
+
+    fn is_healthy(&self) -> bool {
+        !self.storage.is_poisoned() && !self.index.is_poisoned() && !self.rotation.is_poisoned()
+    }
+
+    fn active_epoch(&self) -> KeyEpoch {
+        self.index().epoch()
+    }
+
+    fn stats(&self, name: &str) -> CollectionStats {
+        let staged_epoch = self
+            .rotation
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|r| r.index.epoch());
+        let storage = self.storage.read().unwrap();
+        let index = self.index();
+        let last_update = storage.last_update().max(index.last_update());
+        CollectionStats {
+            name: name.to_string(),
+            epoch: index.epoch(),
+            documents: storage.len() as u64,
+            index_entries: index.len() as u64,
+            document_bytes: storage.size_bytes(),
+            index_bytes: index.size_bytes(),
+            last_update_ms: last_update.map(unix_ms),
+            staged_epoch,
+        }
+    }
+
+    // Runs `f` over the staged state of the given epoch, staging is started
+    // on the first upload and restarted if the client switches to another epoch
+    fn with_rotation<F>(&self, epoch: KeyEpoch, f: F) -> HttpResponse
+    where
+        F: FnOnce(&mut Rotation) -> HttpResponse,
+    {
+        let mut rotation = self.rotation.lock().unwrap();
+        if epoch <= self.active_epoch() {
+            return HttpResponse::Conflict().body("Key epoch is not newer than the active one");
+        }
+        let rotation = match rotation.as_mut() {
+            Some(r) if r.index.epoch() == epoch => r,
+            _ => rotation.insert(Rotation::new(epoch)),
+        };
+        f(rotation)
+    }
+
+    // Runs `f` over the active documents with the active epoch. They are not
+    // written while a rotation is staged, the commit would drop the writes
+    fn with_active_storage<F>(&self, f: F) -> HttpResponse
+    where
+        F: FnOnce(&mut EncryptedDocumentStorage, KeyEpoch) -> HttpResponse,
+    {
+        let rotation = self.rotation.lock().unwrap();
+        if let Some(staged) = rotation.as_ref() {
+            return rotation_staged(staged.index.epoch());
+        }
+        let mut storage = self.storage.write().unwrap();
+        f(&mut storage, self.active_epoch())
+    }
+
+    // Runs `f` over the active index, see `with_active_storage`
+    fn with_active_index<F>(&self, f: F) -> HttpResponse
+    where
+        F: FnOnce(&EncryptedIndex) -> HttpResponse,
+    {
+        let rotation = self.rotation.lock().unwrap();
+        if let Some(staged) = rotation.as_ref() {
+            return rotation_staged(staged.index.epoch());
+        }
+        f(&self.index())
+    }
+
+    // Drops the state staged for `epoch`, returns whether there was any
+    fn abort(&self, epoch: KeyEpoch) -> bool {
+        let mut rotation = self.rotation.lock().unwrap();
+        match rotation.as_ref() {
+            Some(r) if r.index.epoch() == epoch => {
+                *rotation = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Switches to the staged state of `epoch`, the state of the previous
+    // epoch is dropped. Returns the previous epoch and the number of its
+    // documents that were not staged again, or None if nothing is staged
+    // for `epoch`
+    fn commit(&self, epoch: KeyEpoch) -> Option<(KeyEpoch, usize)> {
+        let mut rotation = self.rotation.lock().unwrap();
+        let staged = match rotation.take() {
+            Some(r) if r.index.epoch() == epoch => r,
+            other => {
+                *rotation = other;
+                return None;
+            }
+        };
+
+        let mut storage = self.storage.write().unwrap();
+        let mut index = self.index.write().unwrap();
+        let previous = index.epoch();
+        let dropped = storage
+            .documents
+            .keys()
+            .filter(|id| staged.storage.get(**id).is_none())
+            .count();
+        *storage = staged.storage;
+        *index = Arc::new(staged.index);
+        Some((previous, dropped))
+    }
+}
+
+fn rotation_staged(epoch: KeyEpoch) -> HttpResponse {
+    HttpResponse::Conflict().body(format!(
+        "Key rotation to epoch {} is staged, it has to be committed first",
+        epoch
+    ))
+}
+
+// Turns a response that rejects a batch of a streamed upload into an error
+fn accepted(response: HttpResponse, reason: &'static str) -> Result<(), actix_web::Error> {
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(error::InternalError::from_response(reason, response).into())
+    }
+}
+
+// Tenant of the bearer token of the request
+struct Authenticated {
+    state: web::Data<ServerState>,
+    name: String,
+}
+
+impl Authenticated {
+    fn tenant(&self) -> &Tenant {
+        &self.state.tenants[&self.name]
+    }
+}
+
+impl FromRequest for Authenticated {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+fn authenticate(req: &HttpRequest) -> Result<Authenticated, actix_web::Error> {
+    let state = req
+        .app_data::<web::Data<ServerState>>()
+        .expect("server state is registered")
+        .clone();
+    let name = bearer_token(req)
+        .and_then(|token| state.tenant(token))
+        .ok_or_else(|| unauthorized("Missing or invalid API token"))?
+        .to_string();
+    Ok(Authenticated { state, name })
+}
+
+// Operator authenticated with the admin token, only it can read
+// the monitoring routes as they report on every tenant
+struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let state = req
+            .app_data::<web::Data<ServerState>>()
+            .expect("server state is registered");
+        ready(match bearer_token(req) {
+            Some(token) if state.is_admin(token) => Ok(Admin),
+            _ => Err(unauthorized("Missing or invalid admin token")),
+        })
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn unauthorized(message: &'static str) -> actix_web::Error {
+    error::InternalError::from_response(
+        "Unauthorized",
+        HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .body(message),
+    )
+    .into()
+}
+
+// Collection addressed by the `{collection}` path segment, it is looked up
+// only among collections of the authenticated tenant, so collections of
+// other tenants cannot be accessed and their names are not disclosed
+struct CollectionRef {
+    collection: Arc<Collection>,
+    tenant: String,
+    name: String,
+}
+
+impl Deref for CollectionRef {
+    type Target = Collection;
+
+    fn deref(&self) -> &Collection {
+        &self.collection
+    }
+}
+
+impl FromRequest for CollectionRef {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req).and_then(|auth| {
+            let name = req.match_info().get("collection").unwrap_or_default();
+            let collection = auth
+                .tenant()
+                .collection(name)
+                .ok_or_else(|| error::ErrorNotFound("Collection not found"))?;
+            Ok(CollectionRef {
+                collection,
+                tenant: auth.name,
+                name: name.to_string(),
+            })
+        }))
+    }
+}
+
+// Path parameters of collection routes
+#[derive(Deserialize)]
+struct CollectionPath {
+    collection: String,
+}
+
+#[derive(Deserialize)]
+struct DocumentPath {
+    id: u64,
+}
+
+// Query parameters of collection creation, a client that has rotated
+// keys creates a lost collection again under its key epoch
+#[derive(Deserialize)]
+struct CreateOptions {
+    #[serde(default)]
+    epoch: KeyEpoch,
+}
+
+#[derive(Deserialize)]
+struct EpochPath {
+    epoch: KeyEpoch,
+}
+
+#[derive(Deserialize)]
+struct RotatedDocumentPath {
+    epoch: KeyEpoch,
+    id: u64,
+}
+
+// Request body decoded according to its Content-Type,
+// requests without Content-Type are treated as JSON
+struct Wire<T>(T);
+
+impl<T> Wire<T> {
+    fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Wire<T> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let format = match req.headers().get(CONTENT_TYPE) {
+            None => Some(WireFormat::Json),
+            Some(value) => value.to_str().ok().and_then(WireFormat::from_content_type),
+        };
+        let body = web::Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            let format = format
+                .ok_or_else(|| error::ErrorUnsupportedMediaType("Unsupported content type"))?;
+            let body = body.await?;
+            format
+                .decode(&body)
+                .map(Wire)
+                .map_err(error::ErrorBadRequest)
+        })
+    }
+}
+
+fn request_format(req: &HttpRequest) -> Result<WireFormat, actix_web::Error> {
+    match req.headers().get(CONTENT_TYPE) {
+        None => Ok(WireFormat::Json),
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(WireFormat::from_content_type)
+            .ok_or_else(|| error::ErrorUnsupportedMediaType("Unsupported content type")),
+    }
+}
+
+// Serializes response in the format requested by the Accept header
+fn negotiated<T: Serialize>(req: &HttpRequest, value: &T) -> HttpResponse {
+    let format = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(WireFormat::from_accept)
+        .unwrap_or(WireFormat::Json);
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(format.encode(value))
+}
+
+// Query parameters of document writes
+#[derive(Deserialize)]
+struct WriteOptions {
+    #[serde(default)]
+    overwrite: bool,
+}
+
+// Entity tag of a stored document, the nonce is unique for every encryption
+fn etag(document: &EncryptedDocument) -> EntityTag {
+    EntityTag::new_strong(
+        document
+            .nonce
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+// Stores a document under `id`. A new document is created, an existing one
+// is replaced only if the request has If-Match header matching it or asks
+// to overwrite explicitly, otherwise it is a conflict, so a client bug
+// cannot silently clobber stored documents. Storing the same ciphertext
+// again succeeds, so a client can retry a write whose response was lost
+fn store_document(
+    db: &mut EncryptedDocumentStorage,
+    id: u64,
+    document: EncryptedDocument,
+    epoch: KeyEpoch,
+    if_match: Option<IfMatch>,
+    overwrite: bool,
+) -> HttpResponse {
+    if document.id != id {
+        return HttpResponse::BadRequest().body("Document id does not match the path");
+    }
+    if document.epoch != epoch {
+        return HttpResponse::BadRequest().body("Document is encrypted under another key epoch");
+    }
+
+    let existing = db.get(id);
+    if let Err((status, message)) = check_write(existing, &document, if_match.as_ref(), overwrite) {
+        return HttpResponse::build(status).body(message);
+    }
+
+    let created = existing.is_none();
+    let tag = etag(&document);
+    db.add(document);
+    let mut response = if created {
+        HttpResponse::Created()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.insert_header(ETag(tag)).finish()
+}
+
+// Preconditions of a write of `document` over the stored one, see
+// `store_document`. Returns the status and the reason of a refusal
+fn check_write(
+    existing: Option<&EncryptedDocument>,
+    document: &EncryptedDocument,
+    if_match: Option<&IfMatch>,
+    overwrite: bool,
+) -> Result<(), (StatusCode, &'static str)> {
+    if existing == Some(document) {
+        return Ok(());
+    }
+    match (existing, if_match) {
+        (None, Some(_)) => Err((StatusCode::PRECONDITION_FAILED, "Document does not exist")),
+        (Some(current), Some(IfMatch::Items(tags)))
+            if !tags.iter().any(|tag| tag.strong_eq(&etag(current))) =>
+        {
+            Err((StatusCode::PRECONDITION_FAILED, "Document was modified"))
+        }
+        (Some(_), None) if !overwrite => Err((StatusCode::CONFLICT, "Document already exists")),
+        _ => Ok(()),
+    }
+}
+
+async fn list_collections(auth: Authenticated, req: HttpRequest) -> impl Responder {
+    let collections = auth.tenant().collections.read().unwrap();
+    let mut stats: Vec<CollectionStats> = collections
+        .iter()
+        .map(|(name, collection)| collection.stats(name))
+        .collect();
+    stats.sort_by(|a, b| a.name.cmp(&b.name));
+    negotiated(&req, &stats)
+}
+
+async fn create_collection(
+    auth: Authenticated,
+    path: web::Path<CollectionPath>,
+    options: web::Query<CreateOptions>,
+) -> impl Responder {
+    let name = path.into_inner().collection;
+    if !is_valid_name(&name) {
+        return HttpResponse::BadRequest().body("Invalid collection name");
+    }
+    let mut collections = auth.tenant().collections.write().unwrap();
+    if collections.contains_key(&name) {
+        return HttpResponse::Conflict().body("Collection already exists");
+    }
+    collections.insert(name.clone(), Arc::new(Collection::new(options.epoch)));
+    info!(tenant = %auth.name, collection = %name, epoch = options.epoch, "collection created");
+    HttpResponse::Created().finish()
+}
+
+// Drops the collection with all its documents and index, requests
+// that already hold it finish against the dropped state
+async fn drop_collection(auth: Authenticated, path: web::Path<CollectionPath>) -> impl Responder {
+    let name = path.into_inner().collection;
+    let removed = auth.tenant().collections.write().unwrap().remove(&name);
+    match removed {
+        Some(_) => {
+            info!(tenant = %auth.name, collection = %name, "collection dropped");
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().body("Collection not found"),
+    }
+}
+
+async fn collection_stats(data: CollectionRef, req: HttpRequest) -> impl Responder {
+    negotiated(&req, &data.stats(&data.name))
+}
+
+// Handler to store a document
+async fn put_document(
+    data: CollectionRef,
+    req: HttpRequest,
+    path: web::Path<DocumentPath>,
+    options: web::Query<WriteOptions>,
+    doc: Wire<EncryptedDocument>,
+) -> impl Responder {
+    let id = path.id;
+    data.with_active_storage(|db, epoch| {
+        let response = store_document(
+            db,
+            id,
+            doc.into_inner(),
+            epoch,
+            req.get_header::<IfMatch>(),
+            options.overwrite,
+        );
+        if response.status().is_success() {
+            debug!(
+                tenant = %data.tenant,
+                collection = %data.name,
+                id,
+                total = db.len(),
+                "document stored"
+            );
+        }
+        response
+    })
+}
+
+async fn get_document(
+    data: CollectionRef,
+    req: HttpRequest,
+    path: web::Path<DocumentPath>,
+) -> impl Responder {
+    let db = data.storage.read().unwrap();
+    let id = path.id;
+    match db.get(id) {
+        Some(doc) => {
+            let mut response = negotiated(&req, doc);
+            response.headers_mut().insert(
+                actix_web::http::header::ETAG,
+                etag(doc).to_string().parse().unwrap(),
+            );
+            response
+        }
+        None => HttpResponse::NotFound().body("Document not found"),
+    }
+}
+
+// Index entries pointing to the document are left in place, the
+// client rewrites or drops them with its next index upload
+async fn delete_document(data: CollectionRef, path: web::Path<DocumentPath>) -> impl Responder {
+    data.with_active_storage(|db, _| match db.remove(path.id) {
+        Some(_) => {
+            debug!(
+                tenant = %data.tenant,
+                collection = %data.name,
+                id = path.id,
+                total = db.len(),
+                "document deleted"
+            );
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().body("Document not found"),
+    })
+}
+
+// Handler to store many documents in one request. Every document is
+// written like with a single PUT, If-Match lists the entity tags of the
+// documents that are replaced. The batch is rejected as a whole if any of
+// the writes is, storing the same ciphertext again succeeds, so a batch
+// can be sent again
+async fn upload_documents(
+    data: CollectionRef,
+    req: HttpRequest,
+    options: web::Query<WriteOptions>,
+    docs: Wire<Vec<EncryptedDocument>>,
+) -> impl Responder {
+    let documents = docs.into_inner();
+    let count = documents.len();
+    data.with_active_storage(|db, epoch| {
+        let response = store_documents(
+            db,
+            documents,
+            epoch,
+            req.get_header::<IfMatch>(),
+            options.overwrite,
+        );
+        if response.status().is_success() {
+            info!(
+                tenant = %data.tenant,
+                collection = %data.name,
+                count,
+                total = db.len(),
+                "documents stored"
+            );
+        }
+        response
+    })
+}
+
+fn store_documents(
+    db: &mut EncryptedDocumentStorage,
+    documents: Vec<EncryptedDocument>,
+    epoch: KeyEpoch,
+    if_match: Option<IfMatch>,
+    overwrite: bool,
+) -> HttpResponse {
+    let mut ids = HashSet::new();
+    if let Some(d) = documents.iter().find(|d| !ids.insert(d.id)) {
+        return HttpResponse::BadRequest().body(format!("Duplicate document id {} in batch", d.id));
+    }
+    if documents.iter().any(|d| d.epoch != epoch) {
+        return HttpResponse::BadRequest().body("Document is encrypted under another key epoch");
+    }
+
+    let refused: Vec<(u64, (StatusCode, &str))> = documents
+        .iter()
+        .filter_map(|d| {
+            // tags are matched against the documents the batch replaces,
+            // so one batch can both replace and add documents
+            let existing = db.get(d.id);
+            check_write(
+                existing,
+                d,
+                if_match.as_ref().filter(|_| existing.is_some()),
+                overwrite,
+            )
+            .err()
+            .map(|reason| (d.id, reason))
+        })
+        .collect();
+    if let Some(&(_, (status, message))) = refused.first() {
+        let ids: Vec<String> = refused
+            .iter()
+            .filter(|(_, reason)| reason.0 == status)
+            .map(|(id, _)| id.to_string())
+            .collect();
+        return HttpResponse::build(status).body(format!("{}: {}", message, ids.join(", ")));
+    }
+
+    let count = documents.len();
+    for document in documents {
+        db.add(document);
+    }
+    HttpResponse::Ok().body(format!("{} documents indexed", count))
+}
+
+// Handler to fetch many documents in one request, the response is
+// aligned with requested ids and has `null` for missing ones
+async fn get_documents(
+    data: CollectionRef,
+    req: HttpRequest,
+    ids: Wire<Vec<u64>>,
+) -> impl Responder {
+    let db = data.storage.read().unwrap();
+    let documents: Vec<Option<&EncryptedDocument>> =
+        ids.into_inner().into_iter().map(|id| db.get(id)).collect();
+    negotiated(&req, &documents)
+}
+
+async fn update_index(data: CollectionRef, upd: Wire<EncryptedIndexUpdate>) -> impl Responder {
+    let update = &upd.into_inner();
+    data.with_active_index(|index| {
+        index.update(update);
+        info!(
+            tenant = %data.tenant,
+            collection = %data.name,
+            records = update.len(),
+            total = index.len(),
+            "index updated"
+        );
+        HttpResponse::Ok().body("Index updated")
+    })
+}
+
+// Streamed index upload, records are parsed as chunks of the body arrive
+// and inserted in bounded batches, so the body is never kept in memory
+// as a whole. Searches are served while the records are inserted
+async fn stream_index(
+    data: CollectionRef,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut total = 0;
+    let mut active = |records| {
+        let response = data.with_active_index(|index| {
+            index.extend(records);
+            total = index.len() as u64;
+            HttpResponse::Ok().finish()
+        });
+        accepted(response, "Index update rejected")
+    };
+    // an upload during a rotation is rejected before its body is read
+    active(Vec::new())?;
+    let mut report = ingest(&req, payload, active).await?;
+    report.total = total;
+
+    info!(
+        tenant = %data.tenant,
+        collection = %data.name,
+        records = report.records,
+        batches = report.batches,
+        total = report.total,
+        "index updated from stream"
+    );
+    Ok(negotiated(&req, &report))
+}
+
+// Decodes index records of a streamed body in the format of its Content-Type
+// and passes them to `apply` in batches of at most STREAM_BATCH_SIZE records.
+// Batches applied before a malformed record or a rejected batch are kept
+async fn ingest<F>(
+    req: &HttpRequest,
+    mut payload: web::Payload,
+    mut apply: F,
+) -> Result<IngestReport, actix_web::Error>
+where
+    F: FnMut(Vec<EncryptedTerm2Document>) -> Result<(), actix_web::Error>,
+{
+    let mut decoder = RecordDecoder::new(request_format(req)?);
+    let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+    let mut report = IngestReport {
+        records: 0,
+        batches: 0,
+        total: 0,
+    };
+
+    let malformed = |e, report: &IngestReport| {
+        error::ErrorBadRequest(format!("{} ({} records were applied)", e, report.records))
+    };
+
+    while let Some(chunk) = payload.next().await {
+        decoder.push(&chunk?);
+        while let Some(record) = decoder.next_record::<EncryptedTerm2Document>() {
+            batch.push(record.map_err(|e| malformed(e, &report))?);
+            if batch.len() == STREAM_BATCH_SIZE {
+                apply_batch(&mut apply, &mut batch, &mut report)?;
+            }
+        }
+    }
+    if let Some(record) = decoder.finish().map_err(|e| malformed(e, &report))? {
+        batch.push(record);
+    }
+    apply_batch(&mut apply, &mut batch, &mut report)?;
+    Ok(report)
+}
+
+fn apply_batch<F>(
+    apply: &mut F,
+    batch: &mut Vec<EncryptedTerm2Document>,
+    report: &mut IngestReport,
+) -> Result<(), actix_web::Error>
+where
+    F: FnMut(Vec<EncryptedTerm2Document>) -> Result<(), actix_web::Error>,
+{
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let records = batch.len();
+    apply(std::mem::replace(
+        batch,
+        Vec::with_capacity(STREAM_BATCH_SIZE),
+    ))?;
+
+    report.records += records as u64;
+    report.batches += 1;
+    Ok(())
+}
+
+// Staged documents are not visible until the commit, they are overwritten,
+// so a rotation that failed halfway can be run again from the start
+async fn put_rotated_document(
+    data: CollectionRef,
+    path: web::Path<RotatedDocumentPath>,
+    doc: Wire<EncryptedDocument>,
+) -> impl Responder {
+    let RotatedDocumentPath { epoch, id } = path.into_inner();
+    let document = doc.into_inner();
+    data.with_rotation(epoch, |rotation| {
+        store_document(&mut rotation.storage, id, document, epoch, None, true)
+    })
+}
+
+async fn update_rotated_index(
+    data: CollectionRef,
+    path: web::Path<EpochPath>,
+    upd: Wire<EncryptedIndexUpdate>,
+) -> impl Responder {
+    let epoch = path.epoch;
+    let update = upd.into_inner();
+    data.with_rotation(epoch, |rotation| {
+        rotation.index.update(&update);
+        HttpResponse::Ok().body("Index staged")
+    })
+}
+
+async fn upload_rotated_documents(
+    data: CollectionRef,
+    path: web::Path<EpochPath>,
+    docs: Wire<Vec<EncryptedDocument>>,
+) -> impl Responder {
+    let epoch = path.epoch;
+    let documents = docs.into_inner();
+    data.with_rotation(epoch, |rotation| {
+        store_documents(&mut rotation.storage, documents, epoch, None, true)
+    })
+}
+
+// Streamed upload of the index of a new key epoch, see `stream_index`
+async fn stream_rotated_index(
+    data: CollectionRef,
+    req: HttpRequest,
+    path: web::Path<EpochPath>,
+    payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let epoch = path.epoch;
+    let mut total = 0;
+    let mut staged = |records| {
+        let response = data.with_rotation(epoch, |rotation| {
+            rotation.index.extend(records);
+            total = rotation.index.len() as u64;
+            HttpResponse::Ok().finish()
+        });
+        accepted(response, "Rotation rejected")
+    };
+    // an upload for an epoch that cannot be staged is rejected before its body is read
+    staged(Vec::new())?;
+    let mut report = ingest(&req, payload, staged).await?;
+    report.total = total;
+
+    info!(
+        tenant = %data.tenant,
+        collection = %data.name,
+        epoch,
+        records = report.records,
+        total = report.total,
+        "index staged from stream"
+    );
+    Ok(negotiated(&req, &report))
+}
+
+// Atomically switches the server to the staged key epoch,
+// index and documents of the previous epoch are dropped
+async fn commit_rotation(data: CollectionRef, path: web::Path<EpochPath>) -> impl Responder {
+    let epoch = path.epoch;
+    let Some((previous, removed)) = data.commit(epoch) else {
+        return HttpResponse::NotFound().body("No rotation staged for this key epoch");
+    };
+    info!(
+        tenant = %data.tenant,
+        collection = %data.name,
+        previous,
+        epoch,
+        documents = data.storage.read().unwrap().len(),
+        index = data.index().len(),
+        dropped = removed,
+        "key epoch switched"
+    );
+    HttpResponse::Ok().body("Key epoch switched")
+}
+
+// Drops a staged rotation, a client that lost track of
+// a rotation it started stages it again from the start
+async fn abort_rotation(data: CollectionRef, path: web::Path<EpochPath>) -> impl Responder {
+    let epoch = path.epoch;
+    if !data.abort(epoch) {
+        return HttpResponse::NotFound().body("No rotation staged for this key epoch");
+    }
+    info!(
+        tenant = %data.tenant,
+        collection = %data.name,
+        epoch,
+        "key rotation aborted"
+    );
+    HttpResponse::NoContent().finish()
+}
+
+// Handler to search for a document. Only counts are logged, the
+// keys are the client's PRF outputs and would link queries in logs
+async fn search_doc(
+    data: CollectionRef,
+    req: HttpRequest,
+    request: Wire<Vec<Vec<u8>>>,
+) -> impl Responder {
+    let index = data.index();
+    let query = request.into_inner();
+    let mut encoded_data = Vec::new();
+    let mut found = 0;
+    for term in query.iter() {
+        if let Some(segment) = index.get(term) {
+            encoded_data.push(segment);
+            found += 1;
+        } else {
+            encoded_data.push(vec![]);
+        }
+    }
+    debug!(
+        tenant = %data.tenant,
+        collection = %data.name,
+        found,
+        terms = query.len(),
+        "index searched"
+    );
+    negotiated(&req, &encoded_data)
+}
+
+// Totals over all tenants, tenants and collections are not named
+#[derive(Serialize, Default)]
+struct ServerStats {
+    tenants: u64,
+    collections: u64,
+    documents: u64,
+    document_bytes: u64,
+    index_entries: u64,
+    index_bytes: u64,
+    last_update_ms: Option<u64>,
+}
+
+// Liveness, the process is able to serve requests
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().body("ok")
+}
+
+async fn readyz(state: web::Data<ServerState>) -> impl Responder {
+    if state.is_ready() {
+        HttpResponse::Ok().body("ready")
+    } else {
+        HttpResponse::ServiceUnavailable().body("not ready")
+    }
+}
+
+async fn server_stats(_: Admin, req: HttpRequest, state: web::Data<ServerState>) -> impl Responder {
+    let mut totals = ServerStats {
+        tenants: state.tenants.len() as u64,
+        ..ServerStats::default()
+    };
+    for (_, stats) in state.collection_stats() {
+        totals.collections += 1;
+        totals.documents += stats.documents;
+        totals.document_bytes += stats.document_bytes;
+        totals.index_entries += stats.index_entries;
+        totals.index_bytes += stats.index_bytes;
+        totals.last_update_ms = totals.last_update_ms.max(stats.last_update_ms);
+    }
+    negotiated(&req, &totals)
+}
+
+// Sizes are collected on scrape, so they cannot go stale
+async fn render_metrics(
+    _: Admin,
+    state: web::Data<ServerState>,
+    metrics: web::Data<Metrics>,
+) -> impl Responder {
+    let mut tenants: HashMap<&str, (u64, u64, u64)> = state
+        .tenants
+        .keys()
+        .map(|name| (name.as_str(), (0, 0, 0)))
+        .collect();
+    for (tenant, stats) in state.collection_stats() {
+        let totals = tenants.entry(tenant).or_default();
+        totals.0 += 1;
+        totals.1 += stats.documents;
+        totals.2 += stats.index_entries;
+    }
+    for (tenant, (collections, documents, index_entries)) in tenants {
+        metrics.set_tenant(tenant, collections, documents, index_entries);
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+// Records count and latency of every request. Requests are logged
+// with their route pattern, never with headers, which carry tokens
+async fn observe(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+
+    let response = next.call(req).await?;
+    let status = response.status().as_u16();
+    let elapsed = started.elapsed();
+    if let Some(metrics) = metrics {
+        metrics.observe(&method, &route, status, elapsed.as_secs_f64());
+    }
+    debug!(
+        %method,
+        %route,
+        status,
+        elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+        "request handled"
+    );
+    Ok(response)
+}
+
+// Application with every route of the API, shared by the server binary and
+// tests. Bodies larger than `max_body_size` bytes are rejected
+pub fn app(
+    state: web::Data<ServerState>,
+    metrics: web::Data<Metrics>,
+    max_body_size: usize,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(state)
+        .app_data(metrics)
+        .app_data(web::PayloadConfig::new(max_body_size))
+        .wrap(from_fn(observe))
+        .configure(routes)
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        // monitoring
+        .route("/healthz", web::get().to(healthz))
+        .route("/readyz", web::get().to(readyz))
+        .route("/stats", web::get().to(server_stats))
+        .route("/metrics", web::get().to(render_metrics))
+        // collections of the tenant
+        .route("/collections", web::get().to(list_collections))
+        .service(
+            web::scope("/collections/{collection}")
+                .route("", web::put().to(create_collection))
+                .route("", web::get().to(collection_stats))
+                .route("", web::delete().to(drop_collection))
+                // encrypted documents
+                .route("/documents/{id}", web::put().to(put_document))
+                .route("/documents/{id}", web::get().to(get_document))
+                .route("/documents/{id}", web::delete().to(delete_document))
+                .route("/documents:batch", web::post().to(upload_documents))
+                .route("/documents:get", web::post().to(get_documents))
+                // encrypted index
+                .route("/index", web::post().to(update_index))
+                .route("/index/stream", web::post().to(stream_index))
+                .route("/index/search", web::post().to(search_doc))
+                // key rotation
+                .route(
+                    "/rotate/{epoch}/documents/{id}",
+                    web::put().to(put_rotated_document),
+                )
+                .route(
+                    "/rotate/{epoch}/documents:batch",
+                    web::post().to(upload_rotated_documents),
+                )
+                .route(
+                    "/rotate/{epoch}/index",
+                    web::post().to(update_rotated_index),
+                )
+                .route(
+                    "/rotate/{epoch}/index/stream",
+                    web::post().to(stream_rotated_index),
+                )
+                .route("/rotate/{epoch}", web::delete().to(abort_rotation))
+                .route("/rotate/{epoch}/commit", web::post().to(commit_rotation)),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    const ROTATIONS: KeyEpoch = 20_000;
+
+    #[test]
+    fn test_stats_during_commit() {
+        let collection = Arc::new(Collection::new(0));
+        let (done, finished) = mpsc::channel();
+
+        // stats and commit take the same locks, in the wrong
+        // order they deadlock within a few rotations
+        let mut threads = Vec::new();
+        for _ in 0..2 {
+            let collection = collection.clone();
+            let done = done.clone();
+            threads.push(thread::spawn(move || {
+                while collection.active_epoch() < ROTATIONS {
+                    collection.stats("notes");
+                }
+                done.send(()).unwrap();
+            }));
+        }
+        let committer = collection.clone();
+        threads.push(thread::spawn(move || {
+            for epoch in 1..=ROTATIONS {
+                committer.with_rotation(epoch, |rotation| {
+                    rotation.index.add(vec![1], vec![2]);
+                    HttpResponse::Ok().finish()
+                });
+                assert!(committer.commit(epoch).is_some());
+            }
+            done.send(()).unwrap();
+        }));
+
+        for _ in 0..threads.len() {
+            finished
+                .recv_timeout(Duration::from_secs(30))
+                .expect("stats and commit deadlocked");
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(collection.stats("notes").epoch, ROTATIONS);
+        assert_eq!(collection.commit(1), None);
+    }
+
+    fn document(id: u64, epoch: KeyEpoch) -> EncryptedDocument {
+        EncryptedDocument {
+            id,
+            epoch,
+            algorithm: Default::default(),
+            compression: Default::default(),
+            nonce: vec![0; 12],
+            ciphertext: vec![id as u8; 16],
+        }
+    }
+
+    #[test]
+    fn test_writes_during_rotation() {
+        let collection = Collection::new(0);
+        let stored = collection.with_active_storage(|db, epoch| {
+            store_documents(
+                db,
+                vec![document(1, epoch), document(2, epoch)],
+                epoch,
+                None,
+                false,
+            )
+        });
+        assert!(stored.status().is_success());
+
+        collection.with_rotation(1, |rotation| {
+            rotation.storage.add(document(1, 1));
+            HttpResponse::Ok().finish()
+        });
+        // the commit would replace the active documents and index
+        let put = collection.with_active_storage(|db, epoch| {
+            store_document(db, 3, document(3, epoch), epoch, None, false)
+        });
+        assert_eq!(put.status(), StatusCode::CONFLICT);
+        let index = collection.with_active_index(|_| HttpResponse::Ok().finish());
+        assert_eq!(index.status(), StatusCode::CONFLICT);
+
+        // document 2 was not staged again
+        assert_eq!(collection.commit(1), Some((0, 1)));
+        let storage = collection.storage.read().unwrap();
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.get(1).unwrap().epoch, 1);
+        assert!(storage.get(3).is_none());
+    }
+}
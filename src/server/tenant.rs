@@ -0,0 +1,32 @@
+use serde::Deserialize;
+use std::fmt;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+// Tenant and collection names are used in paths
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Client authenticated with a bearer token, every tenant
+// has its own namespace of collections
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    pub token: String,
+}
+
+// Tokens are never printed
+impl fmt::Debug for TenantConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TenantConfig")
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
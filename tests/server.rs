@@ -0,0 +1,661 @@
+// Routes of the server driven in process with the library indexer, the
+// same requests the command line client sends on flush, search and rotate
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use ebm25::server::{app, Metrics, ServerState, TenantConfig};
+use ebm25::{
+    CollectionStats, CorpusDocument, Document, EncryptedDocument, Indexer, IngestReport, MasterKey,
+    WireFormat, BINARY_CONTENT_TYPE, JSON_CONTENT_TYPE,
+};
+
+const TOKEN: &str = "test-token-0000001";
+const OTHER_TOKEN: &str = "test-token-0000002";
+const ADMIN_TOKEN: &str = "test-admin-token-01";
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+const CORPUS: &[&str] = &[
+    "the quick brown fox jumps over the lazy dog",
+    "a lazy dog sleeps in the sun",
+    "foxes and a fox hunt at night",
+    "brown bears live in the forest",
+];
+
+fn state() -> web::Data<ServerState> {
+    let tenant = |name: &str, token: &str| TenantConfig {
+        name: name.to_string(),
+        token: token.to_string(),
+    };
+    web::Data::new(
+        ServerState::new(&[tenant("default", TOKEN), tenant("other", OTHER_TOKEN)])
+            .with_admin_token(ADMIN_TOKEN),
+    )
+}
+
+fn server() -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    app(state(), web::Data::new(Metrics::new()), MAX_BODY_SIZE)
+}
+
+fn indexer() -> (Indexer, Vec<EncryptedDocument>) {
+    let mut indexer = Indexer::with_collection("notes", MasterKey::generate());
+    let corpus = CORPUS
+        .iter()
+        .enumerate()
+        .map(|(i, text)| CorpusDocument {
+            id: Some(i as u64 + 1),
+            title: format!("doc {}", i + 1),
+            body: text.to_string(),
+        })
+        .collect();
+    let documents = indexer.import_all(corpus).unwrap();
+    (indexer, documents)
+}
+
+fn request(method: &str, path: &str) -> test::TestRequest {
+    let request = match method {
+        "GET" => test::TestRequest::get(),
+        "PUT" => test::TestRequest::put(),
+        "POST" => test::TestRequest::post(),
+        "DELETE" => test::TestRequest::delete(),
+        _ => unreachable!(),
+    };
+    request
+        .uri(path)
+        .insert_header((AUTHORIZATION, format!("Bearer {}", TOKEN)))
+}
+
+// Body encoded in `format`, the response is asked for in the same format
+fn encoded<T: serde::Serialize + ?Sized>(
+    request: test::TestRequest,
+    format: WireFormat,
+    value: &T,
+) -> test::TestRequest {
+    request
+        .insert_header((CONTENT_TYPE, format.content_type()))
+        .insert_header((ACCEPT, format.content_type()))
+        .set_payload(format.encode(value))
+}
+
+// Searches the collection and fetches the ranked documents, like `ebm25 search`
+macro_rules! search {
+    ($app:expr, $indexer:expr, $format:expr, $text:expr) => {{
+        let query = $indexer.query($text.to_string());
+        let response = test::call_service(
+            &$app,
+            encoded(
+                request("POST", "/collections/notes/index/search"),
+                $format,
+                &query.query,
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let values: Vec<Vec<u8>> = $format.decode(&test::read_body(response).await).unwrap();
+        let ranked = $indexer.rank(&query, &values);
+
+        let ids: Vec<u64> = ranked.iter().map(|(id, _)| *id).collect();
+        let response = test::call_service(
+            &$app,
+            encoded(
+                request("POST", "/collections/notes/documents:get"),
+                $format,
+                &ids,
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let fetched: Vec<Option<EncryptedDocument>> =
+            $format.decode(&test::read_body(response).await).unwrap();
+        ids.iter()
+            .zip(fetched)
+            .map(|(id, document)| $indexer.decrypt(*id, &document.unwrap()).unwrap())
+            .collect::<Vec<Document>>()
+    }};
+}
+
+#[actix_web::test]
+async fn test_flush_search_and_decrypt() {
+    let app = test::init_service(server()).await;
+    let (indexer, documents) = indexer();
+    let format = WireFormat::Binary;
+
+    let response =
+        test::call_service(&app, request("PUT", "/collections/notes").to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // flush: documents in a batch, then the index as a stream of records.
+    // The batch is sent twice, like a retry after a lost response
+    for _ in 0..2 {
+        let response = test::call_service(
+            &app,
+            encoded(
+                request("POST", "/collections/notes/documents:batch"),
+                format,
+                &documents,
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let update = indexer.get_encrypted_index();
+    let records = update.len() as u64;
+    let body: Vec<u8> = update
+        .into_records()
+        .iter()
+        .flat_map(|record| format.encode_record(record))
+        .collect();
+    let response = test::call_service(
+        &app,
+        request("POST", "/collections/notes/index/stream")
+            .insert_header((CONTENT_TYPE, BINARY_CONTENT_TYPE))
+            .insert_header((ACCEPT, BINARY_CONTENT_TYPE))
+            .set_payload(body)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: IngestReport = format.decode(&test::read_body(response).await).unwrap();
+    assert_eq!(report.records, records);
+    assert_eq!(report.total, records);
+
+    let found = search!(app, indexer, format, "fox");
+    let titles: Vec<&str> = found.iter().map(|d| d.title.as_str()).collect();
+    assert_eq!(titles, ["doc 3", "doc 1"]);
+    assert_eq!(found[0].content, CORPUS[2]);
+
+    let found = search!(app, indexer, format, "lazy brown");
+    assert_eq!(found.len(), 3);
+    assert!(search!(app, indexer, format, "unknown").is_empty());
+
+    // single documents are served as JSON and decrypt the same
+    let response = test::call_service(
+        &app,
+        request("GET", "/collections/notes/documents/2")
+            .insert_header((ACCEPT, JSON_CONTENT_TYPE))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().contains_key("etag"));
+    let document: EncryptedDocument = test::read_body_json(response).await;
+    assert_eq!(indexer.decrypt(2, &document).unwrap().content, CORPUS[1]);
+}
+
+#[actix_web::test]
+async fn test_rotate_keys() {
+    let app = test::init_service(server()).await;
+    let (mut indexer, documents) = indexer();
+    let format = WireFormat::Json;
+
+    test::call_service(&app, request("PUT", "/collections/notes").to_request()).await;
+    let response = test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/documents:batch"),
+            format,
+            &documents,
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/index"),
+            format,
+            &indexer.get_encrypted_index(),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // the client stages documents in batches and streams the index
+    let epoch = indexer.rotate_keys();
+    let rotated: Vec<EncryptedDocument> = documents
+        .iter()
+        .map(|document| indexer.reencrypt(document.id, document).unwrap())
+        .collect();
+    let path = format!("/collections/notes/rotate/{}/documents:batch", epoch);
+    let response = test::call_service(
+        &app,
+        encoded(request("POST", &path), format, &rotated).to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // a rotation that failed halfway is run again, staged
+    // documents are replaced by their new ciphertexts
+    let rotated: Vec<EncryptedDocument> = documents
+        .iter()
+        .map(|document| indexer.reencrypt(document.id, document).unwrap())
+        .collect();
+    let response = test::call_service(
+        &app,
+        encoded(request("POST", &path), format, &rotated).to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let path = format!(
+        "/collections/notes/rotate/{}/documents/{}",
+        epoch, rotated[0].id
+    );
+    let response = test::call_service(
+        &app,
+        encoded(request("PUT", &path), format, &rotated[0]).to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let update = indexer.get_encrypted_index();
+    let records = update.len() as u64;
+    let body: Vec<u8> = update
+        .into_records()
+        .iter()
+        .flat_map(|record| format.encode_record(record))
+        .collect();
+    let path = format!("/collections/notes/rotate/{}/index/stream", epoch);
+    let response = test::call_service(
+        &app,
+        request("POST", &path)
+            .insert_header((CONTENT_TYPE, JSON_CONTENT_TYPE))
+            .insert_header((ACCEPT, JSON_CONTENT_TYPE))
+            .set_payload(body)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report: IngestReport = test::read_body_json(response).await;
+    assert_eq!(report.records, records);
+    assert_eq!(report.total, records);
+
+    // documents of another epoch are not staged
+    let path = format!("/collections/notes/rotate/{}/documents:batch", epoch);
+    let response = test::call_service(
+        &app,
+        encoded(request("POST", &path), format, &documents).to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // the active documents and index are not written while a rotation is
+    // staged, the commit would drop the writes
+    let writes = [
+        encoded(
+            request("PUT", "/collections/notes/documents/1?overwrite=true"),
+            format,
+            &documents[0],
+        ),
+        encoded(
+            request("POST", "/collections/notes/documents:batch"),
+            format,
+            &documents[..1],
+        ),
+        request("DELETE", "/collections/notes/documents/2"),
+        request("POST", "/collections/notes/index/stream")
+            .insert_header((CONTENT_TYPE, JSON_CONTENT_TYPE))
+            .set_payload(Vec::new()),
+    ];
+    for write in writes {
+        let response = test::call_service(&app, write.to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    // staged documents and index are not searched before the commit
+    assert!(search!(app, indexer, format, "fox").is_empty());
+
+    let path = format!("/collections/notes/rotate/{}/commit", epoch);
+    let response = test::call_service(&app, request("POST", &path).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let found = search!(app, indexer, format, "fox");
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].content, CORPUS[2]);
+
+    // every document is served re-encrypted, none was dropped
+    let response =
+        test::call_service(&app, request("GET", "/collections/notes").to_request()).await;
+    let stats: CollectionStats = test::read_body_json(response).await;
+    assert_eq!((stats.epoch, stats.documents), (epoch, CORPUS.len() as u64));
+    assert_eq!(stats.staged_epoch, None);
+
+    // a rotation the client lost track of is dropped
+    let path = format!("/collections/notes/rotate/{}", epoch + 1);
+    let staged = test::call_service(
+        &app,
+        encoded(
+            request("POST", &format!("{}/index", path)),
+            format,
+            &indexer.get_encrypted_index(),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(staged.status(), StatusCode::OK);
+    let response = test::call_service(&app, request("DELETE", &path).to_request()).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = test::call_service(&app, request("DELETE", &path).to_request()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // a lost collection is created again under the epoch of the client
+    let response = test::call_service(
+        &app,
+        request("PUT", &format!("/collections/restored?epoch={}", epoch)).to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response =
+        test::call_service(&app, request("GET", "/collections/restored").to_request()).await;
+    let stats: CollectionStats = test::read_body_json(response).await;
+    assert_eq!(stats.epoch, epoch);
+}
+
+#[actix_web::test]
+async fn test_rejects_malformed_requests() {
+    let app = test::init_service(server()).await;
+    let (indexer, documents) = indexer();
+
+    test::call_service(&app, request("PUT", "/collections/notes").to_request()).await;
+    let cases = vec![
+        // authentication and collections
+        (
+            test::TestRequest::get().uri("/collections").to_request(),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            test::TestRequest::get()
+                .uri("/collections/notes")
+                .insert_header((AUTHORIZATION, "Bearer not-a-valid-token"))
+                .to_request(),
+            StatusCode::UNAUTHORIZED,
+        ),
+        (
+            test::TestRequest::get()
+                .uri("/collections/notes")
+                .insert_header((AUTHORIZATION, format!("Bearer {}", OTHER_TOKEN)))
+                .to_request(),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            request("GET", "/collections/missing").to_request(),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            request("PUT", "/collections/not%20valid").to_request(),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            request("PUT", "/collections/notes").to_request(),
+            StatusCode::CONFLICT,
+        ),
+        // bodies
+        (
+            request("POST", "/collections/notes/index/search")
+                .insert_header((CONTENT_TYPE, JSON_CONTENT_TYPE))
+                .set_payload("{\"not\": \"a list of keys\"}")
+                .to_request(),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            request("POST", "/collections/notes/documents:batch")
+                .insert_header((CONTENT_TYPE, BINARY_CONTENT_TYPE))
+                .set_payload(vec![0xff; 7])
+                .to_request(),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            request("POST", "/collections/notes/index")
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload("keys")
+                .to_request(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ),
+        (
+            request("POST", "/collections/notes/index/search")
+                .insert_header((CONTENT_TYPE, JSON_CONTENT_TYPE))
+                .set_payload(vec![b' '; MAX_BODY_SIZE + 1])
+                .to_request(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+        // a truncated record of a streamed index upload
+        (
+            request("POST", "/collections/notes/index/stream")
+                .insert_header((CONTENT_TYPE, BINARY_CONTENT_TYPE))
+                .set_payload(vec![64, 0, 0, 0, 1, 2, 3])
+                .to_request(),
+            StatusCode::BAD_REQUEST,
+        ),
+        // documents
+        (
+            encoded(
+                request("PUT", "/collections/notes/documents/99"),
+                WireFormat::Json,
+                &documents[0],
+            )
+            .to_request(),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            request("GET", "/collections/notes/documents/99").to_request(),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            request("GET", "/collections/notes/documents/not-an-id").to_request(),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            request("POST", "/collections/notes/rotate/0/commit").to_request(),
+            StatusCode::NOT_FOUND,
+        ),
+    ];
+    for (i, (request, expected)) in cases.into_iter().enumerate() {
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            expected,
+            "case {}",
+            i
+        );
+    }
+
+    // a batch with another ciphertext of a stored document is rejected as a whole
+    let response = test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/documents:batch"),
+            WireFormat::Binary,
+            &documents[..1],
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut changed = documents.clone();
+    changed[0] = indexer.reencrypt(1, &documents[0]).unwrap();
+    let response = test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/documents:batch"),
+            WireFormat::Binary,
+            &changed,
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // batches with a repeated id or a document of another epoch are malformed
+    let mut other_epoch = documents[1].clone();
+    other_epoch.epoch = 1;
+    for batch in [
+        vec![documents[1].clone(), documents[1].clone()],
+        vec![other_epoch],
+    ] {
+        let response = test::call_service(
+            &app,
+            encoded(
+                request("POST", "/collections/notes/documents:batch"),
+                WireFormat::Json,
+                &batch,
+            )
+            .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // If-Match applies to every stored document of a batch like to a single PUT
+    let response = test::call_service(
+        &app,
+        request("GET", "/collections/notes/documents/1").to_request(),
+    )
+    .await;
+    let tag = response.headers().get(ETAG).unwrap().clone();
+    let batch = |tag: &str| {
+        encoded(
+            request("POST", "/collections/notes/documents:batch"),
+            WireFormat::Json,
+            &changed[..1],
+        )
+        .insert_header((IF_MATCH, tag))
+        .to_request()
+    };
+    let response = test::call_service(&app, batch("\"stale\"")).await;
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = test::call_service(&app, batch(tag.to_str().unwrap())).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/documents:get"),
+            WireFormat::Json,
+            &[1u64, 2],
+        )
+        .to_request(),
+    )
+    .await;
+    let fetched: Vec<Option<EncryptedDocument>> = test::read_body_json(response).await;
+    assert!(fetched[0].is_some());
+    assert!(fetched[1].is_none());
+    assert_eq!(
+        indexer
+            .decrypt(1, fetched[0].as_ref().unwrap())
+            .unwrap()
+            .content,
+        CORPUS[0]
+    );
+
+    // new documents of a batch are added along with the replaced ones
+    let response = test::call_service(
+        &app,
+        request("GET", "/collections/notes/documents/1").to_request(),
+    )
+    .await;
+    let tag = response.headers().get(ETAG).unwrap().clone();
+    let mixed = vec![
+        indexer.reencrypt(1, &changed[0]).unwrap(),
+        documents[1].clone(),
+    ];
+    let response = test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/documents:batch"),
+            WireFormat::Json,
+            &mixed,
+        )
+        .insert_header((IF_MATCH, tag))
+        .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/documents:get"),
+            WireFormat::Json,
+            &[1u64, 2],
+        )
+        .to_request(),
+    )
+    .await;
+    let fetched: Vec<Option<EncryptedDocument>> = test::read_body_json(response).await;
+    assert_eq!(
+        fetched,
+        vec![Some(mixed[0].clone()), Some(mixed[1].clone())]
+    );
+
+    // every rejected request left the server serving
+    let response =
+        test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn test_monitoring_requires_admin_token() {
+    let app = test::init_service(server()).await;
+    let (_, documents) = indexer();
+
+    test::call_service(&app, request("PUT", "/collections/notes").to_request()).await;
+    test::call_service(
+        &app,
+        encoded(
+            request("POST", "/collections/notes/documents:batch"),
+            WireFormat::Json,
+            &documents,
+        )
+        .to_request(),
+    )
+    .await;
+
+    let response =
+        test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    for path in ["/metrics", "/stats"] {
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // a tenant token does not grant access to the stats of other tenants
+        let response = test::call_service(&app, request("GET", path).to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    let admin = |path: &str| {
+        test::TestRequest::get()
+            .uri(path)
+            .insert_header((AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN)))
+            .to_request()
+    };
+    let response = test::call_service(&app, admin("/metrics")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let text = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(text.contains("ebm25_documents{tenant=\"default\"} 4"));
+    let response = test::call_service(&app, admin("/stats")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let stats: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(stats["documents"], 4);
+
+    // without an admin token the monitoring routes are not served at all
+    let state = web::Data::new(ServerState::new(&[]));
+    let app = test::init_service(ebm25::server::app(
+        state,
+        web::Data::new(Metrics::new()),
+        MAX_BODY_SIZE,
+    ))
+    .await;
+    let response = test::call_service(&app, admin("/metrics")).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,231 @@
+// Ranking of the encrypted pipeline (`Indexer`, `EncryptedIndex` and
+// `Indexer::rank`) against tantivy's BM25 over the same analyzer. Documents
+// are kept under 40 tokens, tantivy stores longer lengths lossily (field
+// norms) and its scores would drift from the exact ones. Tantivy scores
+// are f32, so scores are compared with a relative tolerance
+
+use ebm25::{analyzer, group_by, tokenize, CorpusDocument, EncryptedIndex, Indexer};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tantivy::collector::TopDocs;
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, FAST, STORED,
+};
+use tantivy::{doc, Index, IndexReader, Term};
+
+const TOLERANCE: f64 = 1e-5;
+
+const CORPUS: &[&str] = &[
+    "The quick brown fox jumps over the lazy dog",
+    "A lazy dog sleeps in the sun all day long",
+    "Foxes are quick and clever animals, the fox hunts at night",
+    "Brown bears and black bears live in the forest",
+    "The forest is quiet at night, only the owls are awake",
+    "Quick thinking saved the day",
+    "Dogs and cats are the most popular pets",
+    "The cat sat on the mat and watched the dog",
+    "Searchable encryption lets a server search encrypted documents",
+    "The server never sees the documents or the queries in plaintext",
+    "BM25 ranks documents by term frequency and inverse document frequency",
+    "Term frequency saturates, long documents are normalized by their length",
+    "Encrypted search with ranking leaks the number of matching documents",
+    "The client decrypts documents and ranks them locally",
+    "fox fox fox fox",
+    "brown",
+    "Running runners run quickly through the running track",
+    "A brown dog and a brown fox met a brown bear in the brown forest",
+    "Night owls hunt mice, foxes hunt rabbits",
+    "Documents, documents and more documents",
+];
+
+const QUERIES: &[&str] = &[
+    "fox",
+    "brown fox",
+    "lazy dog",
+    "the",
+    "quick brown fox jumps",
+    "forest night",
+    "encrypted documents",
+    "search ranking leaks",
+    "running",
+    "hunt",
+    "term frequency length",
+    "bear owl cat",
+    "missing words only",
+];
+
+struct Tantivy {
+    reader: IndexReader,
+    id: Field,
+    body: Field,
+}
+
+impl Tantivy {
+    fn new(documents: &[(u64, String)]) -> Self {
+        let mut schema = Schema::builder();
+        let id = schema.add_u64_field("id", FAST | STORED);
+        let indexing = TextFieldIndexing::default()
+            .set_tokenizer("ebm25")
+            .set_index_option(IndexRecordOption::WithFreqs);
+        let body = schema.add_text_field(
+            "body",
+            TextOptions::default().set_indexing_options(indexing),
+        );
+        let index = Index::create_in_ram(schema.build());
+        index.tokenizers().register("ebm25", analyzer());
+
+        let mut writer = index.writer_with_num_threads(1, 50_000_000).unwrap();
+        for (document_id, text) in documents {
+            writer
+                .add_document(doc!(id => *document_id, body => text.as_str()))
+                .unwrap();
+        }
+        writer.commit().unwrap();
+
+        let reader = index.reader().unwrap();
+        Self { reader, id, body }
+    }
+
+    // Every distinct term of the query once, the encrypted
+    // pipeline also looks up the postings of a term once
+    fn search(&self, text: &str) -> Vec<(u64, f64)> {
+        let mut terms: Vec<String> = group_by(&tokenize(text)).into_keys().collect();
+        terms.sort();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        let clauses: Vec<(Occur, Box<dyn Query>)> = terms
+            .iter()
+            .map(|term| {
+                let query = TermQuery::new(
+                    Term::from_field_text(self.body, term),
+                    IndexRecordOption::WithFreqs,
+                );
+                (Occur::Should, Box::new(query) as Box<dyn Query>)
+            })
+            .collect();
+
+        let searcher = self.reader.searcher();
+        let limit = searcher.num_docs().max(1) as usize;
+        let found = searcher
+            .search(&BooleanQuery::new(clauses), &TopDocs::with_limit(limit))
+            .unwrap();
+        let mut ranked: Vec<(u64, f64)> = found
+            .into_iter()
+            .map(|(score, address)| {
+                let document = searcher.doc(address).unwrap();
+                let id = document.get_first(self.id).unwrap().as_u64().unwrap();
+                (id, score as f64)
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+    }
+}
+
+fn encrypted_search(indexer: &Indexer, index: &EncryptedIndex, text: &str) -> Vec<(u64, f64)> {
+    let query = indexer.query(text.to_string());
+    let values: Vec<Vec<u8>> = query
+        .query
+        .iter()
+        .map(|key| index.get(key).unwrap_or_default())
+        .collect();
+    indexer.rank(&query, &values)
+}
+
+fn encrypted_index(documents: &[(u64, String)]) -> (Indexer, EncryptedIndex) {
+    let mut indexer = Indexer::new();
+    let corpus = documents
+        .iter()
+        .map(|(id, text)| CorpusDocument {
+            id: Some(*id),
+            title: String::new(),
+            body: text.clone(),
+        })
+        .collect();
+    indexer.import_all(corpus).unwrap();
+    let index = EncryptedIndex::new();
+    index.update(&indexer.get_encrypted_index());
+    (indexer, index)
+}
+
+fn close(a: f64, b: f64) -> bool {
+    (a - b).abs() <= TOLERANCE * a.abs().max(b.abs()).max(1.)
+}
+
+// Same documents in the same order, so the top k agree as well. Documents
+// whose scores are equal within the tolerance may be swapped, f32 and f64
+// scores break ties apart
+fn assert_same_ranking(query: &str, encrypted: &[(u64, f64)], expected: &[(u64, f64)]) {
+    assert_eq!(
+        encrypted.len(),
+        expected.len(),
+        "number of matches of {:?}",
+        query
+    );
+    for (rank, ((id, score), (expected_id, expected_score))) in
+        encrypted.iter().zip(expected).enumerate()
+    {
+        assert!(
+            close(*score, *expected_score),
+            "score at rank {} of {:?}: {} vs tantivy {}",
+            rank,
+            query,
+            score,
+            expected_score
+        );
+        if id != expected_id {
+            let tied = expected
+                .iter()
+                .any(|(other, other_score)| other == id && close(*other_score, *score));
+            assert!(tied, "document at rank {} of {:?}", rank, query);
+        }
+    }
+}
+
+#[test]
+fn test_parity_on_small_corpus() {
+    let documents: Vec<(u64, String)> = CORPUS
+        .iter()
+        .enumerate()
+        .map(|(i, text)| (i as u64 + 1, text.to_string()))
+        .collect();
+    let tantivy = Tantivy::new(&documents);
+    let (indexer, index) = encrypted_index(&documents);
+
+    for query in QUERIES {
+        let expected = tantivy.search(query);
+        let encrypted = encrypted_search(&indexer, &index, query);
+        assert_same_ranking(query, &encrypted, &expected);
+    }
+    assert!(!tantivy.search("fox").is_empty());
+}
+
+#[test]
+fn test_parity_on_synthetic_corpus() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let vocabulary: Vec<String> = (0..300).map(|i| format!("w{}", i)).collect();
+    // a skewed distribution, low words are frequent
+    let word = |rng: &mut StdRng| {
+        let x: f64 = rng.gen();
+        vocabulary[(x * x * x * vocabulary.len() as f64) as usize].clone()
+    };
+
+    let documents: Vec<(u64, String)> = (0..500)
+        .map(|_| {
+            let length = rng.gen_range(1..40);
+            let text: Vec<String> = (0..length).map(|_| word(&mut rng)).collect();
+            (rng.gen(), text.join(" "))
+        })
+        .collect();
+    let tantivy = Tantivy::new(&documents);
+    let (indexer, index) = encrypted_index(&documents);
+
+    for _ in 0..100 {
+        let terms: Vec<String> = (0..rng.gen_range(1..4)).map(|_| word(&mut rng)).collect();
+        let query = terms.join(" ");
+        let expected = tantivy.search(&query);
+        let encrypted = encrypted_search(&indexer, &index, &query);
+        assert_same_ranking(&query, &encrypted, &expected);
+    }
+}